@@ -20,7 +20,7 @@ class BenchmarkBm25Spyrs(Benchmark):
         self.model = bm25spyrs.Retriever(1.5, 0.75)
 
     def indexing_method(self, texts):
-        self.model.index(texts)
+        self.model.index(list(zip(self.doc_ids, texts)))
 
     def compute_mat_size(self):
         mem = self.model.mat_mem()
@@ -43,7 +43,7 @@ class BenchmarkBm25Spyrs(Benchmark):
             total_time += time.time() - start_time
 
             for batch_i, qid in enumerate(query_ids[i:i + chunk_size]):
-                results[qid] = {doc_ids[index]: score for index, score in hits[batch_i]}
+                results[qid] = dict(hits[batch_i])
 
         self.result_tracker['queries_count'] = len(queries)
         self.result_tracker['retrieval_total_time'] = total_time
@@ -0,0 +1,298 @@
+use crate::tokenizer::Vocab;
+
+/// The indexed terms sorted by their text, so that the terms sharing a prefix are contiguous.
+#[derive(Default)]
+pub struct TermDictionary {
+    terms: Vec<(String, u32)>,
+}
+
+impl TermDictionary {
+    pub fn build(vocab: &Vocab) -> TermDictionary {
+        let mut terms: Vec<(String, u32)> = vocab.iter().map(|(term, &id)| (term.clone(), id)).collect();
+        terms.sort_unstable();
+        Self { terms }
+    }
+
+    /// Adds the terms of `vocab` not in the dictionary yet, which are those with the highest ids
+    /// as ids are given in order, merging them in rather than sorting all terms again.
+    pub fn extend(&mut self, vocab: &Vocab) {
+        let n_terms = self.terms.len() as u32;
+        let mut added: Vec<(String, u32)> =
+            vocab.iter().filter(|(_, &id)| id >= n_terms).map(|(term, &id)| (term.clone(), id)).collect();
+        if added.is_empty() {
+            return;
+        }
+        added.sort_unstable();
+
+        let mut terms = Vec::with_capacity(self.terms.len() + added.len());
+        let mut added = added.into_iter().peekable();
+        for term in std::mem::take(&mut self.terms) {
+            while let Some(new_term) = added.next_if(|new_term| *new_term < term) {
+                terms.push(new_term);
+            }
+            terms.push(term);
+        }
+        terms.extend(added);
+        self.terms = terms;
+    }
+
+    /// Terms starting with `prefix`.
+    pub fn with_prefix(&self, prefix: &str) -> &[(String, u32)] {
+        let start = self.terms.partition_point(|(term, _)| term.as_str() < prefix);
+        let len = self.terms[start..].partition_point(|(term, _)| term.starts_with(prefix));
+        &self.terms[start..start + len]
+    }
+
+    /// Ids of the terms at most `max_distance` edits away from `word`, each with its similarity
+    /// `1 - distance / min(len)`, lengths counted in characters. Only the terms sharing the first
+    /// `prefix_length` characters of `word` are considered. Terms are walked in order with a
+    /// Levenshtein automaton whose states are shared along common prefixes, skipping every term
+    /// starting with a prefix from which no match can be reached.
+    pub fn fuzzy(&self, word: &str, max_distance: u32, prefix_length: usize) -> Vec<(u32, f32)> {
+        let chars: Vec<char> = word.chars().collect();
+        let prefix_length = prefix_length.min(chars.len());
+        let prefix: String = chars[..prefix_length].iter().collect();
+        let automaton = LevenshteinAutomaton { word: &chars[prefix_length..], max_distance };
+
+        let candidates = self.with_prefix(&prefix);
+        let mut matches = Vec::new();
+        // `states[k]` is the state after reading the first `k` characters of `read`.
+        let mut states = vec![automaton.start()];
+        let mut read: Vec<char> = Vec::new();
+        let mut i = 0;
+
+        while i < candidates.len() {
+            let (term, id) = &candidates[i];
+            let suffix: Vec<char> = term[prefix.len()..].chars().collect();
+            let common = read.iter().zip(&suffix).take_while(|(a, b)| a == b).count();
+            states.truncate(common + 1);
+
+            let dead_end = suffix[common..].iter().position(|&c| {
+                let state = automaton.step(states.last().unwrap(), c);
+                let can_match = automaton.can_match(&state);
+                if can_match {
+                    states.push(state);
+                }
+                !can_match
+            });
+            read = suffix;
+
+            match dead_end {
+                Some(position) => {
+                    let dead_prefix = &read[..common + position + 1];
+                    i += candidates[i..].partition_point(|(term, _)| {
+                        term[prefix.len()..].chars().take(dead_prefix.len()).eq(dead_prefix.iter().copied())
+                    });
+                    read.truncate(common + position);
+                }
+                None => {
+                    if let Some(distance) = automaton.distance(states.last().unwrap()) {
+                        let min_len = chars.len().min(prefix_length + read.len());
+                        let similarity = 1.0 - distance as f32 / min_len as f32;
+                        if similarity > 0.0 {
+                            matches.push((*id, similarity));
+                        }
+                    }
+                    i += 1;
+                }
+            }
+        }
+        matches
+    }
+
+    /// Ids of the terms matching `pattern`, where `*` stands for any sequence of characters
+    /// and `?` for a single one. Only the terms sharing the literal prefix of the pattern are scanned.
+    pub fn matching(&self, pattern: &str) -> Vec<u32> {
+        let literal_prefix = &pattern[..pattern.find(['*', '?']).unwrap_or(pattern.len())];
+        let pattern: Vec<char> = pattern.chars().collect();
+
+        self.with_prefix(literal_prefix)
+            .iter()
+            .filter(|(term, _)| matches_wildcard(&pattern, &term.chars().collect::<Vec<_>>()))
+            .map(|&(_, id)| id)
+            .collect()
+    }
+}
+
+/// Levenshtein automaton accepting the texts at most `max_distance` edits away from `word`.
+/// A state is the last row of the edit distance matrix between `word` and the text read so far,
+/// distances above `max_distance` being capped so that the states are finitely many.
+struct LevenshteinAutomaton<'a> {
+    word: &'a [char],
+    max_distance: u32,
+}
+
+impl LevenshteinAutomaton<'_> {
+    fn start(&self) -> Vec<u32> {
+        (0..=self.word.len() as u32).map(|distance| distance.min(self.max_distance + 1)).collect()
+    }
+
+    fn step(&self, state: &[u32], c: char) -> Vec<u32> {
+        let mut next = Vec::with_capacity(state.len());
+        next.push((state[0] + 1).min(self.max_distance + 1));
+        for (i, &wc) in self.word.iter().enumerate() {
+            let distance = (state[i] + (wc != c) as u32).min(state[i + 1] + 1).min(next[i] + 1);
+            next.push(distance.min(self.max_distance + 1));
+        }
+        next
+    }
+
+    /// Whether some continuation of the text read can still be accepted.
+    fn can_match(&self, state: &[u32]) -> bool {
+        state.iter().any(|&distance| distance <= self.max_distance)
+    }
+
+    /// Edit distance between `word` and the text read, if accepted.
+    fn distance(&self, state: &[u32]) -> Option<u32> {
+        state.last().copied().filter(|&distance| distance <= self.max_distance)
+    }
+}
+
+/// Glob matching with backtracking to the last `*` only, which is enough as a `*` can absorb
+/// whatever an earlier one would have.
+fn matches_wildcard(pattern: &[char], text: &[char]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    let mut last_star: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                last_star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match last_star {
+                Some((star, star_t)) => {
+                    p = star + 1;
+                    t = star_t + 1;
+                    last_star = Some((star, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every word of 1 to `max_len` characters of `alphabet`.
+    fn words(alphabet: &[char], max_len: usize) -> Vec<String> {
+        let mut words = vec![String::new()];
+        let mut all = Vec::new();
+        for _ in 0..max_len {
+            words = words.iter().flat_map(|word| alphabet.iter().map(move |&c| format!("{word}{c}"))).collect();
+            all.extend(words.iter().cloned());
+        }
+        all
+    }
+
+    /// A vocabulary with a multi-byte character, so that prefixes are sliced on character boundaries.
+    fn vocab() -> Vocab {
+        words(&['a', 'b', 'é'], 4).into_iter().zip(0..).collect()
+    }
+
+    fn levenshtein(a: &[char], b: &[char]) -> u32 {
+        let mut row: Vec<u32> = (0..=b.len() as u32).collect();
+        for (i, &ca) in a.iter().enumerate() {
+            let mut next = vec![i as u32 + 1];
+            for (j, &cb) in b.iter().enumerate() {
+                next.push((row[j] + (ca != cb) as u32).min(row[j + 1] + 1).min(next[j] + 1));
+            }
+            row = next;
+        }
+        row[b.len()]
+    }
+
+    #[test]
+    fn fuzzy_matches_the_terms_within_the_edit_distance() {
+        let vocab = vocab();
+        let dictionary = TermDictionary::build(&vocab);
+
+        for word in words(&['a', 'b', 'é', 'c'], 4) {
+            let chars: Vec<char> = word.chars().collect();
+            for max_distance in 0..=2 {
+                for prefix_length in 0..=2 {
+                    let mut expected: Vec<(u32, f32)> = vocab
+                        .iter()
+                        .filter_map(|(term, &id)| {
+                            let term: Vec<char> = term.chars().collect();
+                            let prefix = prefix_length.min(chars.len());
+                            if !term.starts_with(&chars[..prefix]) {
+                                return None;
+                            }
+                            let distance = levenshtein(&chars, &term);
+                            let similarity = 1.0 - distance as f32 / chars.len().min(term.len()) as f32;
+                            (distance <= max_distance && similarity > 0.0).then_some((id, similarity))
+                        })
+                        .collect();
+                    expected.sort_by_key(|&(id, _)| id);
+
+                    let mut matches = dictionary.fuzzy(&word, max_distance, prefix_length);
+                    matches.sort_by_key(|&(id, _)| id);
+                    assert_eq!(matches, expected, "{word:?} within {max_distance} sharing {prefix_length}");
+                }
+            }
+        }
+    }
+
+    /// Glob matching trying every split of the text at each `*`.
+    fn glob(pattern: &[char], text: &[char]) -> bool {
+        match pattern.split_first() {
+            None => text.is_empty(),
+            Some(('*', rest)) => (0..=text.len()).any(|skipped| glob(rest, &text[skipped..])),
+            Some((&c, rest)) => text.first().is_some_and(|&t| c == '?' || c == t) && glob(rest, &text[1..]),
+        }
+    }
+
+    #[test]
+    fn extending_sorts_the_added_terms_in() {
+        let mut vocab = Vocab::new();
+        let mut dictionary = TermDictionary::default();
+        for batch in [&["b", "d", "ab"][..], &[], &["a", "c", "da", "e"], &["aa"]] {
+            for &term in batch {
+                let id = vocab.len() as u32;
+                vocab.insert(term.to_string(), id);
+            }
+            dictionary.extend(&vocab);
+            assert_eq!(dictionary.terms, TermDictionary::build(&vocab).terms);
+        }
+    }
+
+    #[test]
+    fn wildcards_match_like_globs() {
+        let texts: Vec<Vec<char>> = std::iter::once(String::new()).chain(words(&['a', 'b'], 6)).map(|text| text.chars().collect()).collect();
+        for pattern in words(&['a', 'b', '*', '?'], 5) {
+            let pattern: Vec<char> = pattern.chars().collect();
+            for text in &texts {
+                assert_eq!(matches_wildcard(&pattern, text), glob(&pattern, text), "{pattern:?} on {text:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn matching_scans_the_terms_of_the_literal_prefix() {
+        let vocab = vocab();
+        let dictionary = TermDictionary::build(&vocab);
+
+        for pattern in words(&['a', 'é', '*', '?'], 4) {
+            let chars: Vec<char> = pattern.chars().collect();
+            let mut expected: Vec<u32> = vocab
+                .iter()
+                .filter(|(term, _)| glob(&chars, &term.chars().collect::<Vec<_>>()))
+                .map(|(_, &id)| id)
+                .collect();
+            expected.sort_unstable();
+
+            let mut matches = dictionary.matching(&pattern);
+            matches.sort_unstable();
+            assert_eq!(matches, expected, "{pattern:?}");
+        }
+    }
+}
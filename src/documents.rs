@@ -0,0 +1,213 @@
+use std::collections::{HashMap, HashSet};
+use std::io;
+use pyo3::{FromPyObject, IntoPyObject};
+use crate::persistence::{invalid_data, Buffer, Reader, Writer};
+
+const MISSING: u32 = u32::MAX;
+
+/// Bitmap of deleted internal document ids.
+#[derive(Default)]
+pub struct Tombstones {
+    words: Vec<u64>,
+    count: usize,
+}
+
+impl Tombstones {
+    pub fn insert(&mut self, doc: usize) {
+        let (word, bit) = (doc / 64, doc % 64);
+        if word >= self.words.len() {
+            self.words.resize(word + 1, 0);
+        }
+        if self.words[word] & (1 << bit) == 0 {
+            self.words[word] |= 1 << bit;
+            self.count += 1;
+        }
+    }
+
+    #[inline]
+    pub fn contains(&self, doc: usize) -> bool {
+        self.words.get(doc / 64).is_some_and(|word| word & (1 << (doc % 64)) != 0)
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+}
+
+/// How users refer to a document: the name it was indexed with, or else its position.
+#[derive(Clone, FromPyObject, IntoPyObject)]
+pub enum DocId {
+    Position(usize),
+    Name(String),
+}
+
+/// Keeps the ids returned to users stable while documents are deleted, replaced and compacted.
+/// Internal ids index the concatenated segments; public ids are the positions documents were
+/// added at, and survive updates and compaction. Documents indexed with a name are returned by name.
+#[derive(Default)]
+pub struct Documents {
+    public_ids: Vec<u32>,
+    internal_ids: Vec<u32>,
+    deleted: Tombstones,
+    names: HashMap<u32, String>,
+    positions: HashMap<String, u32>,
+}
+
+impl Documents {
+    /// Registers `count` new documents appended after the existing internal ids.
+    pub fn push(&mut self, count: usize) {
+        for _ in 0..count {
+            let internal = self.public_ids.len() as u32;
+            self.public_ids.push(self.internal_ids.len() as u32);
+            self.internal_ids.push(internal);
+        }
+    }
+
+    /// Registers new named documents appended after the existing internal ids.
+    /// Names must not belong to a live document; the name of a deleted one can be reused.
+    pub fn push_named(&mut self, names: Vec<String>) {
+        for name in names {
+            let public = self.internal_ids.len() as u32;
+            self.push(1);
+            if let Some(previous) = self.positions.insert(name.clone(), public) {
+                self.names.remove(&previous);
+            }
+            self.names.insert(public, name);
+        }
+    }
+
+    /// Returns the first name that is repeated or already used by a live document.
+    pub fn find_conflict<'a>(&self, names: &'a [String]) -> Option<&'a str> {
+        let mut seen = HashSet::with_capacity(names.len());
+        names.iter()
+            .find(|&name| {
+                let is_live = self.positions.get(name).is_some_and(|&public| self.internal_id(public as usize).is_some());
+                !seen.insert(name) || is_live
+            })
+            .map(String::as_str)
+    }
+
+    /// Resolves a user-facing id to the position of a live document.
+    pub fn position(&self, doc_id: &DocId) -> Option<usize> {
+        let public = match doc_id {
+            DocId::Position(position) => *position,
+            DocId::Name(name) => *self.positions.get(name)? as usize,
+        };
+        self.internal_id(public).map(|_| public)
+    }
+
+    pub fn doc_id(&self, internal: usize) -> DocId {
+        let public = self.public_ids[internal];
+        match self.names.get(&public) {
+            Some(name) => DocId::Name(name.clone()),
+            None => DocId::Position(public as usize),
+        }
+    }
+
+    /// Points `public` at a document appended after the existing internal ids.
+    pub fn push_replacement(&mut self, public: usize) {
+        self.internal_ids[public] = self.public_ids.len() as u32;
+        self.public_ids.push(public as u32);
+    }
+
+    pub fn internal_id(&self, public: usize) -> Option<usize> {
+        self.internal_ids.get(public)
+            .filter(|&&internal| internal != MISSING)
+            .map(|&internal| internal as usize)
+    }
+
+    /// Tombstones the document currently behind `public`, returning false if there is none.
+    pub fn delete(&mut self, public: usize) -> bool {
+        match self.internal_id(public) {
+            Some(internal) => {
+                self.deleted.insert(internal);
+                self.internal_ids[public] = MISSING;
+                true
+            }
+            None => false,
+        }
+    }
+
+    #[inline]
+    pub fn is_deleted(&self, internal: usize) -> bool {
+        self.deleted.contains(internal)
+    }
+
+    pub fn n_deleted(&self) -> usize {
+        self.deleted.len()
+    }
+
+    /// Drops tombstoned documents and renumbers the survivors contiguously, in order.
+    /// Returns the new internal id of every old internal id, `None` for deleted ones.
+    pub fn compact(&mut self) -> Vec<Option<u32>> {
+        let mut remap = Vec::with_capacity(self.public_ids.len());
+        let mut public_ids = Vec::with_capacity(self.public_ids.len() - self.deleted.len());
+
+        for (internal, &public) in self.public_ids.iter().enumerate() {
+            if self.deleted.contains(internal) {
+                remap.push(None);
+            } else {
+                let new_internal = public_ids.len() as u32;
+                self.internal_ids[public as usize] = new_internal;
+                public_ids.push(public);
+                remap.push(Some(new_internal));
+            }
+        }
+
+        self.public_ids = public_ids;
+        self.deleted = Tombstones::default();
+        remap
+    }
+
+    pub fn write(&self, writer: &mut Writer) -> io::Result<()> {
+        writer.write_array(&self.public_ids)?;
+        writer.write_array(&self.internal_ids)?;
+
+        writer.write_u64(self.names.len() as u64)?;
+        for (&public, name) in &self.names {
+            writer.write_u32(public)?;
+            writer.write_str(name)?;
+        }
+        Ok(())
+    }
+
+    pub fn read(reader: &mut Reader, n_docs: usize) -> io::Result<Documents> {
+        let public_ids: Buffer<u32> = reader.read_array()?;
+        let internal_ids: Buffer<u32> = reader.read_array()?;
+
+        let is_consistent = public_ids.len() == n_docs
+            && public_ids.iter().all(|&public| (public as usize) < internal_ids.len())
+            && internal_ids.iter().all(|&internal| internal == MISSING || (internal as usize) < n_docs);
+        if !is_consistent {
+            return Err(invalid_data("inconsistent document ids"));
+        }
+
+        let n_names = reader.read_u64()?;
+        let mut names = HashMap::new();
+        for _ in 0..n_names {
+            let public = reader.read_u32()?;
+            if public as usize >= internal_ids.len() {
+                return Err(invalid_data("inconsistent document names"));
+            }
+            names.insert(public, reader.read_str()?);
+        }
+
+        let mut documents = Self {
+            public_ids: public_ids.to_vec(),
+            internal_ids: internal_ids.to_vec(),
+            deleted: Tombstones::default(),
+            positions: names.iter().map(|(&public, name)| (name.clone(), public)).collect(),
+            names,
+        };
+        for (internal, &public) in public_ids.iter().enumerate() {
+            if documents.internal_ids[public as usize] != internal as u32 {
+                documents.deleted.insert(internal);
+            }
+        }
+        Ok(documents)
+    }
+}
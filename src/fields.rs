@@ -0,0 +1,103 @@
+use std::collections::HashMap;
+use std::io;
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::{PyDict, PyList, PyString};
+use crate::persistence::{Reader, Writer};
+
+/// A named part of multi-field documents, scored with BM25F: the term frequencies of every field
+/// are length-normalized with the field's own `b`, weighted, and summed before saturation.
+#[derive(Clone)]
+pub struct Field {
+    pub name: String,
+    pub weight: f32,
+    pub b: f32,
+}
+
+impl Field {
+    /// Parses a Python dict mapping field names to `(weight, b)` pairs, keeping its order.
+    pub fn extract_all(fields: &Bound<'_, PyDict>) -> PyResult<Vec<Field>> {
+        fields
+            .iter()
+            .map(|(name, params)| {
+                let (weight, b) = params.extract()?;
+                Ok(Self { name: name.extract()?, weight, b })
+            })
+            .collect()
+    }
+
+    /// Term frequency normalized by the field length, before weighting.
+    #[inline]
+    pub fn normalized_tf(&self, tf: f32, field_len: f32, avg_field_len: f32) -> f32 {
+        tf / (1.0 - self.b + self.b * field_len / avg_field_len)
+    }
+
+    /// Splits documents given as dicts of fields into one list per field, in the order of `fields`.
+    /// Missing fields are replaced by `empty`, an empty text or token list.
+    pub fn split<'py>(
+        fields: &[Field],
+        documents: &Bound<'py, PyList>,
+        empty: Bound<'py, PyAny>,
+    ) -> PyResult<Vec<Bound<'py, PyList>>> {
+        let py = documents.py();
+        let mut columns = vec![Vec::with_capacity(documents.len()); fields.len()];
+
+        for document in documents.iter() {
+            let document = document
+                .downcast::<PyDict>()
+                .map_err(|_| PyValueError::new_err("documents must be dicts mapping field names to their content"))?;
+
+            for name in document.keys() {
+                let name = name.downcast::<PyString>()?.to_str()?;
+                if !fields.iter().any(|field| field.name == name) {
+                    return Err(PyValueError::new_err(format!("unknown field {name:?}")));
+                }
+            }
+
+            for (column, field) in columns.iter_mut().zip(fields) {
+                column.push(document.get_item(&field.name)?.unwrap_or_else(|| empty.clone()));
+            }
+        }
+
+        columns.into_iter().map(|column| PyList::new(py, column)).collect()
+    }
+
+    pub fn write(&self, writer: &mut Writer) -> io::Result<()> {
+        writer.write_str(&self.name)?;
+        writer.write_f32(self.weight)?;
+        writer.write_f32(self.b)
+    }
+
+    pub fn read(reader: &mut Reader) -> io::Result<Field> {
+        Ok(Self { name: reader.read_str()?, weight: reader.read_f32()?, b: reader.read_f32()? })
+    }
+}
+
+/// The fields a query searches, as a list of names or a dict mapping names to boosts
+/// that multiply the weights fields were indexed with.
+#[derive(FromPyObject)]
+pub enum FieldSelection {
+    Boosts(HashMap<String, f32>),
+    Names(Vec<String>),
+}
+
+impl FieldSelection {
+    /// Query-time weight of each of `fields`, zero for the fields that are not searched.
+    pub fn weights(&self, fields: &[Field]) -> Result<Vec<f32>, String> {
+        let names: Vec<&String> = match self {
+            FieldSelection::Boosts(boosts) => boosts.keys().collect(),
+            FieldSelection::Names(names) => names.iter().collect(),
+        };
+        if let Some(name) = names.iter().find(|&&name| !fields.iter().any(|field| &field.name == name)) {
+            return Err(format!("unknown field {name:?}"));
+        }
+
+        Ok(fields
+            .iter()
+            .map(|field| match self {
+                FieldSelection::Boosts(boosts) => boosts.get(&field.name).map_or(0.0, |boost| boost * field.weight),
+                FieldSelection::Names(names) => if names.contains(&field.name) { field.weight } else { 0.0 },
+            })
+            .collect())
+    }
+}
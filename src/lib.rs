@@ -1,7 +1,16 @@
 use pyo3::prelude::*;
 use pyo3::types::PyModule;
 
+pub mod dictionary;
+pub mod documents;
+pub mod fields;
+pub mod persistence;
+pub mod positions;
+pub mod pruning;
+pub mod query;
 pub mod retriever;
+pub mod scoring;
+pub mod segment;
 pub mod tokenizer;
 
 #[pymodule]
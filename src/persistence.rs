@@ -0,0 +1,320 @@
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
+use std::mem::size_of;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use crc32fast::Hasher;
+use memmap2::Mmap;
+
+const MAGIC: &[u8; 8] = b"BM25SPRS";
+const HEADER_LEN: u64 = 24;
+const ALIGNMENT: u64 = 8;
+
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Layout of an index file:
+///
+/// | offset | size | content                                  |
+/// |--------|------|------------------------------------------|
+/// | 0      | 8    | magic `BM25SPRS`                         |
+/// | 8      | 4    | format version                            |
+/// | 12     | 4    | crc32 of the payload                      |
+/// | 16     | 8    | payload length in bytes                   |
+/// | 24     | ...  | payload, arrays aligned on 8 bytes        |
+///
+/// All integers and floats are little-endian.
+///
+/// The file is written next to its destination and only renamed over it by `finish`, so that
+/// retrievers still mapping the previous file keep reading it, and a failed save leaves it intact.
+pub struct Writer {
+    inner: BufWriter<File>,
+    hasher: Hasher,
+    position: u64,
+    path: PathBuf,
+    temp_path: PathBuf,
+    is_finished: bool,
+}
+
+impl Writer {
+    pub fn create(path: &Path) -> io::Result<Writer> {
+        let file_name = path.file_name().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file path"))?;
+        let mut temp_name = OsString::from(".");
+        temp_name.push(file_name);
+        temp_name.push(format!(".{}.tmp", std::process::id()));
+        let temp_path = path.with_file_name(temp_name);
+
+        let inner = BufWriter::new(File::create(&temp_path)?);
+        let mut writer = Self { inner, hasher: Hasher::new(), position: HEADER_LEN, path: path.to_path_buf(), temp_path, is_finished: false };
+        writer.inner.write_all(&[0; HEADER_LEN as usize])?;
+        Ok(writer)
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        let payload_len = self.position - HEADER_LEN;
+        let checksum = self.hasher.clone().finalize();
+
+        self.inner.flush()?;
+        let file = self.inner.get_mut();
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(MAGIC)?;
+        file.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        file.write_all(&checksum.to_le_bytes())?;
+        file.write_all(&payload_len.to_le_bytes())?;
+        file.sync_all()?;
+
+        fs::rename(&self.temp_path, &self.path)?;
+        self.is_finished = true;
+        Ok(())
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.inner.write_all(bytes)?;
+        self.hasher.update(bytes);
+        self.position += bytes.len() as u64;
+        Ok(())
+    }
+
+    fn align(&mut self) -> io::Result<()> {
+        let padding = (ALIGNMENT - self.position % ALIGNMENT) % ALIGNMENT;
+        self.write_bytes(&[0; ALIGNMENT as usize][..padding as usize])
+    }
+
+    pub fn write_u32(&mut self, value: u32) -> io::Result<()> {
+        self.write_bytes(&value.to_le_bytes())
+    }
+
+    pub fn write_u64(&mut self, value: u64) -> io::Result<()> {
+        self.write_bytes(&value.to_le_bytes())
+    }
+
+    pub fn write_f32(&mut self, value: f32) -> io::Result<()> {
+        self.write_bytes(&value.to_le_bytes())
+    }
+
+    pub fn write_str(&mut self, value: &str) -> io::Result<()> {
+        self.write_u64(value.len() as u64)?;
+        self.write_bytes(value.as_bytes())
+    }
+
+    pub fn write_array<T: Element>(&mut self, values: &[T]) -> io::Result<()> {
+        self.write_u64(values.len() as u64)?;
+        self.align()?;
+        for &value in values {
+            self.write_bytes(value.to_le_bytes().as_ref())?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        if !self.is_finished {
+            let _ = fs::remove_file(&self.temp_path);
+        }
+    }
+}
+
+/// Plain-old-data element types that can be viewed in place inside a mapped file.
+pub trait Element: Copy + Send + Sync + 'static {
+    type Bytes: AsRef<[u8]>;
+
+    fn to_le_bytes(self) -> Self::Bytes;
+    fn from_le_bytes(bytes: &[u8]) -> Self;
+}
+
+impl Element for u8 {
+    type Bytes = [u8; 1];
+
+    fn to_le_bytes(self) -> Self::Bytes {
+        [self]
+    }
+
+    fn from_le_bytes(bytes: &[u8]) -> Self {
+        bytes[0]
+    }
+}
+
+impl Element for u32 {
+    type Bytes = [u8; 4];
+
+    fn to_le_bytes(self) -> Self::Bytes {
+        u32::to_le_bytes(self)
+    }
+
+    fn from_le_bytes(bytes: &[u8]) -> Self {
+        u32::from_le_bytes(bytes.try_into().unwrap())
+    }
+}
+
+impl Element for f32 {
+    type Bytes = [u8; 4];
+
+    fn to_le_bytes(self) -> Self::Bytes {
+        f32::to_le_bytes(self)
+    }
+
+    fn from_le_bytes(bytes: &[u8]) -> Self {
+        f32::from_le_bytes(bytes.try_into().unwrap())
+    }
+}
+
+/// An array either owned on the heap or living inside a memory-mapped index file.
+/// Mapped buffers are shared through the page cache between every process mapping the same file.
+pub enum Buffer<T: Element> {
+    Owned(Vec<T>),
+    Mapped { map: Arc<Mmap>, offset: usize, len: usize, element: PhantomData<T> },
+}
+
+impl<T: Element> Default for Buffer<T> {
+    fn default() -> Self {
+        Buffer::Owned(Vec::new())
+    }
+}
+
+impl<T: Element> From<Vec<T>> for Buffer<T> {
+    fn from(values: Vec<T>) -> Self {
+        Buffer::Owned(values)
+    }
+}
+
+impl<T: Element> Deref for Buffer<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        match self {
+            Buffer::Owned(values) => values,
+            // SAFETY: the reader only creates mapped buffers for in-bounds, aligned ranges of
+            // a little-endian file, and the map is kept alive by the Arc.
+            Buffer::Mapped { map, offset, len, .. } => unsafe {
+                std::slice::from_raw_parts(map.as_ptr().add(*offset) as *const T, *len)
+            },
+        }
+    }
+}
+
+enum Source {
+    Heap(Vec<u8>),
+    Mapped(Arc<Mmap>),
+}
+
+impl Deref for Source {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Source::Heap(bytes) => bytes,
+            Source::Mapped(map) => map,
+        }
+    }
+}
+
+pub struct Reader {
+    bytes: Source,
+    position: usize,
+}
+
+impl Reader {
+    /// Reads the whole file, only checking its checksum with `verify`.
+    pub fn open(path: &Path, verify: bool) -> io::Result<Reader> {
+        let mut bytes = Vec::new();
+        File::open(path)?.read_to_end(&mut bytes)?;
+        Self::from_source(Source::Heap(bytes), verify)
+    }
+
+    /// Maps the file instead of reading it; arrays are then served straight from the mapping.
+    /// The file must not be modified while any retriever loaded from it is alive.
+    /// Skipping verification avoids touching every page of the file at load time.
+    pub fn map(path: &Path, verify: bool) -> io::Result<Reader> {
+        if cfg!(target_endian = "big") {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "memory-mapped indexes require a little-endian host"));
+        }
+
+        // SAFETY: see the requirement above, the mapping is read-only.
+        let map = unsafe { Mmap::map(&File::open(path)?)? };
+        Self::from_source(Source::Mapped(Arc::new(map)), verify)
+    }
+
+    fn from_source(bytes: Source, verify: bool) -> io::Result<Reader> {
+        if bytes.len() < HEADER_LEN as usize || &bytes[..8] != MAGIC {
+            return Err(invalid_data("not a bm25spyrs index file"));
+        }
+
+        let version = u32::from_le_bytes(bytes[8..12].try_into().unwrap());
+        if version != FORMAT_VERSION {
+            return Err(invalid_data(&format!(
+                "unsupported index format version {version}, expected {FORMAT_VERSION}"
+            )));
+        }
+
+        let checksum = u32::from_le_bytes(bytes[12..16].try_into().unwrap());
+        let payload_len = u64::from_le_bytes(bytes[16..24].try_into().unwrap());
+        let payload = &bytes[HEADER_LEN as usize..];
+
+        if payload.len() as u64 != payload_len {
+            return Err(invalid_data("index file is truncated"));
+        }
+        if verify && crc32fast::hash(payload) != checksum {
+            return Err(invalid_data("index file checksum mismatch"));
+        }
+
+        Ok(Self { bytes, position: HEADER_LEN as usize })
+    }
+
+    fn read_bytes(&mut self, len: usize) -> io::Result<&[u8]> {
+        let end = self.position.checked_add(len)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or_else(|| invalid_data("unexpected end of index file"))?;
+
+        let bytes = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn align(&mut self) -> io::Result<()> {
+        let padding = (ALIGNMENT as usize - self.position % ALIGNMENT as usize) % ALIGNMENT as usize;
+        self.read_bytes(padding).map(|_| ())
+    }
+
+    fn read_len(&mut self) -> io::Result<usize> {
+        usize::try_from(self.read_u64()?).map_err(|_| invalid_data("length overflows usize"))
+    }
+
+    pub fn read_u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.read_bytes(4)?.try_into().unwrap()))
+    }
+
+    pub fn read_u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.read_bytes(8)?.try_into().unwrap()))
+    }
+
+    pub fn read_f32(&mut self) -> io::Result<f32> {
+        Ok(f32::from_le_bytes(self.read_bytes(4)?.try_into().unwrap()))
+    }
+
+    pub fn read_str(&mut self) -> io::Result<String> {
+        let len = self.read_len()?;
+        String::from_utf8(self.read_bytes(len)?.to_vec())
+            .map_err(|_| invalid_data("invalid utf-8 string in index file"))
+    }
+
+    pub fn read_array<T: Element>(&mut self) -> io::Result<Buffer<T>> {
+        let len = self.read_len()?;
+        self.align()?;
+        let offset = self.position;
+        self.read_bytes(len.checked_mul(size_of::<T>()).ok_or_else(|| invalid_data("array too large"))?)?;
+
+        match &self.bytes {
+            Source::Mapped(map) => Ok(Buffer::Mapped { map: map.clone(), offset, len, element: PhantomData }),
+            Source::Heap(bytes) => Ok(Buffer::Owned(
+                bytes[offset..self.position].chunks_exact(size_of::<T>()).map(T::from_le_bytes).collect()
+            )),
+        }
+    }
+}
+
+pub fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
@@ -0,0 +1,229 @@
+use std::io;
+use std::mem::size_of;
+use crate::persistence::{invalid_data, Buffer, Reader, Writer};
+use crate::segment::MatrixComponents;
+
+/// Token positions of the postings of a `MatrixComponents`, in the same order: the positions of
+/// posting `i` are `data[offsets[i]..offsets[i + 1]]`, as increasing deltas encoded in LEB128 varints.
+pub struct Positions {
+    pub offsets: Buffer<u32>,
+    pub data: Buffer<u8>,
+}
+
+fn encode(positions: impl Iterator<Item = u32>, data: &mut Vec<u8>) {
+    let mut previous = 0;
+    for position in positions {
+        let mut delta = position - previous;
+        previous = position;
+
+        while delta >= 0x80 {
+            data.push(delta as u8 | 0x80);
+            delta >>= 7;
+        }
+        data.push(delta as u8);
+    }
+}
+
+impl Positions {
+    /// `documents` yields the `(term, position)` pairs of each document, by increasing position.
+    pub fn build<D: IntoIterator<Item = (u32, u32)>>(documents: impl Iterator<Item = D>, n_terms: usize) -> Positions {
+        let mut term_positions: Vec<Vec<(u32, u32)>> = vec![Vec::new(); n_terms];
+        for (doc, tokens) in documents.enumerate() {
+            for (term, position) in tokens {
+                term_positions[term as usize].push((doc as u32, position));
+            }
+        }
+
+        let mut offsets = vec![0];
+        let mut data = Vec::new();
+        for positions in &term_positions {
+            for doc_positions in positions.chunk_by(|a, b| a.0 == b.0) {
+                encode(doc_positions.iter().map(|&(_, position)| position), &mut data);
+                offsets.push(data.len() as u32);
+            }
+        }
+
+        Self { offsets: offsets.into(), data: data.into() }
+    }
+
+    /// Replaces the content of `positions` by the positions of posting `posting`.
+    pub fn decode(&self, posting: usize, positions: &mut Vec<u32>) {
+        positions.clear();
+        let bytes = &self.data[self.offsets[posting] as usize..self.offsets[posting + 1] as usize];
+
+        let (mut position, mut delta, mut shift) = (0, 0, 0);
+        for &byte in bytes {
+            delta |= ((byte & 0x7f) as u32).wrapping_shl(shift);
+            shift += 7;
+            if byte & 0x80 == 0 {
+                position = delta.wrapping_add(position);
+                positions.push(position);
+                (delta, shift) = (0, 0);
+            }
+        }
+    }
+
+    /// Concatenates the positions of `parts`, each with the postings it belongs to and their number
+    /// of documents, dropping the documents `remap` maps to `None` like `MatrixComponents::merge`.
+    pub fn merge<'a>(
+        parts: impl Iterator<Item = (&'a Positions, &'a MatrixComponents, usize)> + Clone,
+        remap: &[Option<u32>],
+        n_terms: usize,
+    ) -> Positions {
+        let mut offsets = vec![0];
+        let mut data = Vec::new();
+
+        for term in 0..n_terms {
+            let mut doc_offset = 0;
+            for (positions, postings, n_docs) in parts.clone() {
+                let range = postings.posting_range(term);
+                for (posting, &doc) in range.clone().zip(&postings.indices[range]) {
+                    if remap[doc_offset + doc as usize].is_some() {
+                        let bytes = &positions.data[positions.offsets[posting] as usize..positions.offsets[posting + 1] as usize];
+                        data.extend_from_slice(bytes);
+                        offsets.push(data.len() as u32);
+                    }
+                }
+                doc_offset += n_docs;
+            }
+        }
+
+        Self { offsets: offsets.into(), data: data.into() }
+    }
+
+    pub fn mem(&self) -> usize {
+        self.offsets.len() * size_of::<u32>() + self.data.len()
+    }
+
+    pub fn write(&self, writer: &mut Writer) -> io::Result<()> {
+        writer.write_array(&self.offsets)?;
+        writer.write_array(&self.data)
+    }
+
+    pub fn read(reader: &mut Reader, n_postings: usize) -> io::Result<Positions> {
+        let positions = Self { offsets: reader.read_array()?, data: reader.read_array()? };
+
+        let is_consistent = positions.offsets.len() == n_postings + 1
+            && positions.offsets.windows(2).all(|w| w[0] <= w[1])
+            && positions.offsets.last().is_some_and(|&end| end as usize == positions.data.len());
+        if !is_consistent {
+            return Err(invalid_data("inconsistent positions"));
+        }
+
+        Ok(positions)
+    }
+}
+
+/// Counts the non-overlapping occurrences of a phrase in a document, from the positions of each of
+/// its terms, and those of its terms in the phrase. An occurrence takes a distinct position per term,
+/// whose offsets from the positions of the exact phrase differ by at most `slop`, which allows for
+/// gaps and reorderings.
+pub fn phrase_frequency(term_positions: &[Vec<u32>], phrase_positions: &[u32], slop: u32) -> u32 {
+    if term_positions.iter().any(Vec::is_empty) {
+        return 0;
+    }
+
+    let mut cursors = vec![0; term_positions.len()];
+    let mut frequency = 0;
+    loop {
+        let offsets = term_positions
+            .iter()
+            .zip(&cursors)
+            .zip(phrase_positions)
+            .map(|((positions, &cursor), &phrase_position)| positions[cursor] as i64 - phrase_position as i64);
+        let (min_term, min_offset) = offsets.clone().enumerate().min_by_key(|&(_, offset)| offset).unwrap();
+        let max_offset = offsets.max().unwrap();
+
+        let is_distinct = |cursors: &[usize]| {
+            let positions: Vec<u32> = term_positions.iter().zip(cursors).map(|(positions, &cursor)| positions[cursor]).collect();
+            positions.iter().enumerate().all(|(i, position)| !positions[..i].contains(position))
+        };
+
+        if max_offset - min_offset <= slop as i64 && (slop == 0 || is_distinct(&cursors)) {
+            frequency += 1;
+            for (cursor, positions) in cursors.iter_mut().zip(term_positions) {
+                *cursor += 1;
+                if *cursor == positions.len() {
+                    return frequency;
+                }
+            }
+        } else {
+            cursors[min_term] += 1;
+            if cursors[min_term] == term_positions[min_term].len() {
+                return frequency;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn positions_round_trip_through_varints() {
+        let documents: Vec<Vec<(u32, u32)>> = vec![
+            vec![(0, 0), (1, 1), (0, 127), (1, 128), (0, 16_383), (1, 16_384)],
+            vec![(2, 5)],
+            vec![(0, 1 << 21), (2, (1 << 28) + 5), (0, u32::MAX)],
+        ];
+        let positions = Positions::build(documents.iter().cloned(), 3);
+
+        // Postings are ordered by term, then document.
+        let expected: [&[u32]; 5] = [&[0, 127, 16_383], &[1 << 21, u32::MAX], &[1, 128, 16_384], &[5], &[(1 << 28) + 5]];
+        let mut decoded = Vec::new();
+        for (posting, expected) in expected.iter().enumerate() {
+            positions.decode(posting, &mut decoded);
+            assert_eq!(decoded, *expected, "posting {posting}");
+        }
+        assert_eq!(positions.offsets.len(), expected.len() + 1);
+    }
+
+    #[test]
+    fn exact_phrases_need_consecutive_positions() {
+        // "heart attack" in "heart attack ... heart ... attack ... heart attack".
+        assert_eq!(phrase_frequency(&[vec![0, 5, 20], vec![1, 9, 21]], &[0, 1], 0), 2);
+        assert_eq!(phrase_frequency(&[vec![0], vec![2]], &[0, 1], 0), 0);
+        assert_eq!(phrase_frequency(&[vec![0], vec![]], &[0, 1], 0), 0);
+    }
+
+    #[test]
+    fn slop_allows_gaps_and_transpositions() {
+        // "heart x attack".
+        assert_eq!(phrase_frequency(&[vec![0], vec![2]], &[0, 1], 0), 0);
+        assert_eq!(phrase_frequency(&[vec![0], vec![2]], &[0, 1], 1), 1);
+        // "attack heart": a transposition takes a slop of 2.
+        assert_eq!(phrase_frequency(&[vec![1], vec![0]], &[0, 1], 1), 0);
+        assert_eq!(phrase_frequency(&[vec![1], vec![0]], &[0, 1], 2), 1);
+        // "big dog red" for "big red dog".
+        assert_eq!(phrase_frequency(&[vec![0], vec![2], vec![1]], &[0, 1, 2], 1), 0);
+        assert_eq!(phrase_frequency(&[vec![0], vec![2], vec![1]], &[0, 1, 2], 2), 1);
+    }
+
+    #[test]
+    fn repeated_terms_take_distinct_positions() {
+        // "cat cat" in "... cat cat cat", where the phrase occurs twice as with exact phrase matching in Lucene.
+        let cats = vec![3, 4, 5];
+        assert_eq!(phrase_frequency(&[cats.clone(), cats.clone()], &[0, 1], 0), 2);
+        // A single "cat" cannot stand for both terms, whatever the slop.
+        for slop in 0..4 {
+            assert_eq!(phrase_frequency(&[vec![3], vec![3]], &[0, 1], slop), 0);
+            assert_eq!(phrase_frequency(&[vec![3, 9], vec![3, 9]], &[0, 1], slop), 0);
+        }
+        assert_eq!(phrase_frequency(&[vec![3, 5], vec![3, 5]], &[0, 1], 1), 1);
+    }
+
+    #[test]
+    fn stopword_gaps_count_in_both_phrases_and_documents() {
+        // "heart of the attack", stopwords removed, in "heart of the attack" and "heart attack".
+        let phrase = [0, 3];
+        assert_eq!(phrase_frequency(&[vec![0], vec![3]], &phrase, 0), 1);
+        assert_eq!(phrase_frequency(&[vec![0], vec![1]], &phrase, 0), 0);
+        assert_eq!(phrase_frequency(&[vec![0], vec![1]], &phrase, 2), 1);
+        // "heart attack" in "heart of the attack".
+        assert_eq!(phrase_frequency(&[vec![0], vec![3]], &[0, 1], 0), 0);
+        assert_eq!(phrase_frequency(&[vec![0], vec![3]], &[0, 1], 2), 1);
+        // Phrase positions need not start at zero, as when the phrase starts with a stopword.
+        assert_eq!(phrase_frequency(&[vec![7], vec![10]], &[1, 4], 0), 1);
+    }
+}
@@ -0,0 +1,711 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::io;
+use std::mem::size_of;
+use crate::persistence::{invalid_data, Buffer, Reader, Writer};
+use crate::scoring::Scorer;
+use crate::segment::{MatrixComponents, Segment};
+
+/// Lists of `(frequency, document length)` pairs bounding the scores of groups of postings: the
+/// pairs of the postings of a group not dominated by a posting of higher or equal frequency in a
+/// shorter document. Scores being non-decreasing in the frequency and non-increasing in the length,
+/// the best of these pairs bounds every posting of the group whatever the average document length,
+/// and term frequencies being small integers they are few. The pairs of group `i` are
+/// `tfs[offsets[i]..offsets[i + 1]]`, by increasing frequency.
+pub struct Frontiers {
+    pub offsets: Buffer<u32>,
+    pub tfs: Buffer<f32>,
+    pub doc_lengths: Buffer<f32>,
+}
+
+impl Frontiers {
+    /// `groups` yields the `(frequency, document length)` pairs of each group.
+    fn build<G: IntoIterator<Item = (f32, f32)>>(groups: impl Iterator<Item = G>) -> Frontiers {
+        let mut offsets = vec![0];
+        let mut tfs = Vec::new();
+        let mut doc_lengths = Vec::new();
+        let mut pairs = Vec::new();
+
+        for group in groups {
+            pairs.clear();
+            pairs.extend(group);
+            pairs.sort_unstable_by(|a: &(f32, f32), b| b.0.total_cmp(&a.0).then(a.1.total_cmp(&b.1)));
+
+            let start = tfs.len();
+            let mut min_doc_len = f32::INFINITY;
+            for &(tf, doc_len) in &pairs {
+                if doc_len < min_doc_len {
+                    min_doc_len = doc_len;
+                    tfs.push(tf);
+                    doc_lengths.push(doc_len);
+                }
+            }
+            tfs[start..].reverse();
+            doc_lengths[start..].reverse();
+            offsets.push(tfs.len() as u32);
+        }
+
+        Self { offsets: offsets.into(), tfs: tfs.into(), doc_lengths: doc_lengths.into() }
+    }
+
+    /// Upper bound of the `tf_weight` of the postings of `group`, zero for an empty group,
+    /// `None` if `scorer` cannot bound it.
+    fn max_tf_weight(&self, scorer: &dyn Scorer, group: usize, avg_doc_len: f32) -> Option<f32> {
+        let range = self.offsets[group] as usize..self.offsets[group + 1] as usize;
+        self.tfs[range.clone()]
+            .iter()
+            .zip(&self.doc_lengths[range])
+            .try_fold(0.0, |bound: f32, (&tf, &doc_len)| Some(bound.max(scorer.max_tf_weight(tf, doc_len, avg_doc_len)?)))
+    }
+
+    fn mem(&self) -> usize {
+        self.offsets.len() * size_of::<u32>() + (self.tfs.len() + self.doc_lengths.len()) * size_of::<f32>()
+    }
+
+    fn write(&self, writer: &mut Writer) -> io::Result<()> {
+        writer.write_array(&self.offsets)?;
+        writer.write_array(&self.tfs)?;
+        writer.write_array(&self.doc_lengths)
+    }
+
+    fn read(reader: &mut Reader, n_groups: usize) -> io::Result<Frontiers> {
+        let frontiers = Self { offsets: reader.read_array()?, tfs: reader.read_array()?, doc_lengths: reader.read_array()? };
+
+        let is_consistent = frontiers.offsets.len() == n_groups + 1
+            && frontiers.offsets.windows(2).all(|w| w[0] <= w[1])
+            && frontiers.offsets.last().is_some_and(|&end| end as usize == frontiers.tfs.len())
+            && frontiers.tfs.len() == frontiers.doc_lengths.len();
+        if !is_consistent {
+            return Err(invalid_data("inconsistent score bounds"));
+        }
+        Ok(frontiers)
+    }
+}
+
+/// Number of postings per block of `TermBounds`.
+pub const BLOCK_SIZE: usize = 128;
+
+/// Score bounds of the postings of each term of a segment, over its whole postings list in `terms`,
+/// and over each of its blocks of `BLOCK_SIZE` postings in `blocks`, the blocks of term `t` being
+/// the groups `block_offsets[t]..block_offsets[t + 1]`.
+pub struct TermBounds {
+    pub terms: Frontiers,
+    pub block_offsets: Buffer<u32>,
+    pub blocks: Frontiers,
+}
+
+impl TermBounds {
+    pub fn build(postings: &MatrixComponents, doc_lengths: &[f32]) -> TermBounds {
+        let n_terms = postings.indptr.len().saturating_sub(1);
+        let pairs = |doc_indices: &[u32], term_frequencies: &[f32]| {
+            term_frequencies.iter().zip(doc_indices).map(|(&tf, &doc)| (tf, doc_lengths[doc as usize])).collect::<Vec<_>>()
+        };
+
+        let terms = Frontiers::build((0..n_terms).map(|term| {
+            let (doc_indices, term_frequencies) = postings.postings(term);
+            pairs(doc_indices, term_frequencies)
+        }));
+
+        let block_offsets: Vec<u32> = (0..=n_terms)
+            .scan(0, |offset, term| {
+                let block_offset = *offset;
+                *offset += postings.doc_frequency(term).div_ceil(BLOCK_SIZE as u32);
+                Some(block_offset)
+            })
+            .collect();
+        let blocks = Frontiers::build((0..n_terms).flat_map(|term| {
+            let (doc_indices, term_frequencies) = postings.postings(term);
+            doc_indices.chunks(BLOCK_SIZE).zip(term_frequencies.chunks(BLOCK_SIZE)).map(move |(d, t)| pairs(d, t))
+        }));
+
+        Self { terms, block_offsets: block_offsets.into(), blocks }
+    }
+
+    /// Upper bound of the `tf_weight` of the postings of `term`, `None` if `scorer` cannot bound it.
+    pub fn max_tf_weight(&self, scorer: &dyn Scorer, term: usize, avg_doc_len: f32) -> Option<f32> {
+        if term + 1 >= self.terms.offsets.len() {
+            return Some(0.0);
+        }
+        self.terms.max_tf_weight(scorer, term, avg_doc_len)
+    }
+
+    pub fn mem(&self) -> usize {
+        self.terms.mem() + self.block_offsets.len() * size_of::<u32>() + self.blocks.mem()
+    }
+
+    pub fn write(&self, writer: &mut Writer) -> io::Result<()> {
+        self.terms.write(writer)?;
+        writer.write_array(&self.block_offsets)?;
+        self.blocks.write(writer)
+    }
+
+    /// `postings` are those the bounds were built from.
+    pub fn read(reader: &mut Reader, postings: &MatrixComponents) -> io::Result<TermBounds> {
+        let n_terms = postings.indptr.len().saturating_sub(1);
+        let terms = Frontiers::read(reader, n_terms)?;
+        let block_offsets: Buffer<u32> = reader.read_array()?;
+
+        let is_consistent = block_offsets.len() == n_terms + 1
+            && block_offsets.first().is_some_and(|&start| start == 0)
+            && (0..n_terms).all(|term| {
+                let n_blocks = postings.doc_frequency(term).div_ceil(BLOCK_SIZE as u32);
+                block_offsets[term + 1].checked_sub(block_offsets[term]) == Some(n_blocks)
+            });
+        if !is_consistent {
+            return Err(invalid_data("inconsistent score bounds"));
+        }
+
+        let n_blocks = block_offsets.last().copied().unwrap_or(0) as usize;
+        Ok(Self { terms, block_offsets, blocks: Frontiers::read(reader, n_blocks)? })
+    }
+}
+
+/// A document and its score, ordered by score then by decreasing id, so that among equal scores
+/// the documents indexed first rank higher.
+#[derive(Clone, Copy, PartialEq)]
+struct Scored {
+    score: f32,
+    doc: usize,
+}
+
+impl Eq for Scored {}
+
+impl Ord for Scored {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.score.total_cmp(&other.score).then(other.doc.cmp(&self.doc))
+    }
+}
+
+impl PartialOrd for Scored {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// The `n` best scored documents seen so far, in a min-heap. Documents only replace the lowest
+/// one when they score strictly more or score as much with a lower index.
+pub struct TopK {
+    n: usize,
+    heap: BinaryHeap<Reverse<Scored>>,
+}
+
+impl TopK {
+    /// The heap is sized for the top of `n_docs` documents, `n` being unbounded as passed from Python.
+    pub fn new(n: usize, n_docs: usize) -> TopK {
+        Self { n, heap: BinaryHeap::with_capacity(n.min(n_docs) + 1) }
+    }
+
+    pub fn len(&self) -> usize {
+        self.heap.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.heap.is_empty()
+    }
+
+    /// Score a document must exceed to enter, or reach with a lower index, negative infinity until
+    /// `n` documents were pushed.
+    pub fn threshold(&self) -> f32 {
+        match self.heap.peek() {
+            Some(Reverse(lowest)) if self.heap.len() == self.n => lowest.score,
+            _ => f32::NEG_INFINITY,
+        }
+    }
+
+    /// Returns whether the document entered.
+    pub fn push(&mut self, doc: usize, score: f32) -> bool {
+        let scored = Scored { score, doc };
+        if self.heap.len() < self.n {
+            self.heap.push(Reverse(scored));
+        } else if self.heap.peek().is_some_and(|Reverse(lowest)| scored > *lowest) {
+            self.heap.pop();
+            self.heap.push(Reverse(scored));
+        } else {
+            return false;
+        }
+        true
+    }
+
+    /// Documents by decreasing score.
+    pub fn into_sorted(self) -> Vec<(usize, f32)> {
+        self.heap.into_sorted_vec().into_iter().map(|Reverse(scored)| (scored.doc, scored.score)).collect()
+    }
+}
+
+/// Relative margin given to upper bounds, as the scores they bound are summed in another order
+/// and may round differently.
+const BOUND_SLACK: f32 = 1.0 + 1e-5;
+
+/// Whether a document whose score is bounded by `bound` can enter a top whose threshold is `threshold`.
+fn can_enter(bound: f32, threshold: f32) -> bool {
+    bound * BOUND_SLACK > threshold
+}
+
+/// Number of documents whose essential terms are scored at once, see `top_n`.
+const WINDOW: u32 = 4096;
+
+/// Number of blocks of a term whose bounds are looked at in a window.
+const MAX_WINDOW_BLOCKS: usize = 4;
+
+/// The postings of a query term in a segment, walked in document order.
+struct Cursor<'a> {
+    /// Rank of the term in the query, scores summing terms in that order like exhaustive search does.
+    order: usize,
+    doc_indices: &'a [u32],
+    term_frequencies: &'a [f32],
+    position: usize,
+    idf: f32,
+    max_score: f32,
+    blocks: &'a Frontiers,
+    /// Group of the first block of the term in `blocks`.
+    first_block: usize,
+    /// First block that may overlap the current window.
+    block: usize,
+    /// Bound of the scores of the postings in the current window.
+    window_max_score: f32,
+}
+
+impl Cursor<'_> {
+    /// Current document, `u32::MAX` once exhausted.
+    fn doc(&self) -> u32 {
+        self.doc_indices.get(self.position).copied().unwrap_or(u32::MAX)
+    }
+
+    /// Moves to the first document at or after `doc`, returning the frequency of the term in `doc`.
+    /// Targets being usually close, the search gallops before bisecting.
+    fn seek(&mut self, doc: u32) -> Option<f32> {
+        let remaining = &self.doc_indices[self.position..];
+        let mut end = 1;
+        while end < remaining.len() && remaining[end - 1] < doc {
+            end *= 2;
+        }
+        let start = end / 2;
+        let end = end.min(remaining.len());
+        self.position += start + remaining[start..end].partition_point(|&d| d < doc);
+        (self.doc() == doc).then(|| self.term_frequencies[self.position])
+    }
+
+    /// Bounds the scores of the postings of the documents `start..end` with the bounds of the
+    /// blocks overlapping them, zero if there are none. Past `MAX_WINDOW_BLOCKS` blocks, the term
+    /// is frequent enough for its bound over the whole postings list to do as well.
+    fn window_max_score(&mut self, start: u32, end: u32, scorer: &dyn Scorer, avg_doc_len: f32, absent_tf_weight: f32) -> f32 {
+        let n_blocks = self.doc_indices.len().div_ceil(BLOCK_SIZE);
+        let last_doc = |block: usize| self.doc_indices[((block + 1) * BLOCK_SIZE).min(self.doc_indices.len()) - 1];
+        self.block = self.block.max(self.position / BLOCK_SIZE);
+        while self.block < n_blocks && last_doc(self.block) < start {
+            self.block += 1;
+        }
+        if self.doc_indices.get((self.block + MAX_WINDOW_BLOCKS) * BLOCK_SIZE).is_some_and(|&doc| doc < end) {
+            return self.max_score;
+        }
+
+        (self.block..n_blocks)
+            .take_while(|&block| self.doc_indices[block * BLOCK_SIZE] < end)
+            .map(|block| {
+                self.blocks
+                    .max_tf_weight(scorer, self.first_block + block, avg_doc_len)
+                    .map_or(self.max_score, |max_tf_weight| self.idf * (max_tf_weight - absent_tf_weight))
+            })
+            .fold(0.0, f32::max)
+    }
+}
+
+/// Top `n` documents for `terms`, each paired with its weighted IDF, with MaxScore (Turtle and
+/// Flood, 1995) over the block bounds of `TermBounds`, as in Block-Max WAND (Ding and Suel, 2011).
+/// Terms whose bounds together do not exceed the score of the current `n`-th best document are
+/// non-essential, as a document only containing them cannot enter the top. Windows of documents
+/// start at the next posting of an essential term, and are skipped when the bounds of the blocks
+/// overlapping them cannot exceed that score either. In the others, terms are ranked again by the
+/// bounds of these blocks: the postings of the essential terms are scored term at a time, like
+/// exhaustive search, and the documents they make candidates are then looked up in the
+/// non-essential terms while they can still enter the top.
+/// Scores are the sums exhaustive search computes, without the weight of absent terms.
+/// `scores` is a zeroed buffer of one score per document. Returns `None` when `scorer` cannot
+/// bound its term weights. IDFs must not be negative.
+pub fn top_n(
+    scorer: &dyn Scorer,
+    segments: &[Segment],
+    terms: &[(usize, f32)],
+    avg_doc_len: f32,
+    is_deleted: impl Fn(usize) -> bool,
+    n: usize,
+    scores: &mut [f32],
+) -> Option<TopK> {
+    let absent_tf_weight = scorer.absent_tf_weight();
+    let mut top = TopK::new(n, scores.len());
+    let mut doc_offset = 0;
+
+    for segment in segments {
+        let scores = &mut scores[doc_offset..doc_offset + segment.n_docs()];
+        let mut cursors = Vec::with_capacity(terms.len());
+        for (order, &(term, idf)) in terms.iter().enumerate() {
+            let (doc_indices, term_frequencies) = segment.postings.postings(term);
+            if doc_indices.is_empty() {
+                continue;
+            }
+            let max_tf_weight = segment.bounds.max_tf_weight(scorer, term, avg_doc_len)?;
+            cursors.push(Cursor {
+                order,
+                doc_indices,
+                term_frequencies,
+                position: 0,
+                idf,
+                max_score: idf * (max_tf_weight - absent_tf_weight),
+                blocks: &segment.bounds.blocks,
+                first_block: segment.bounds.block_offsets[term] as usize,
+                block: 0,
+                window_max_score: 0.0,
+            });
+        }
+
+        cursors.sort_unstable_by(|a, b| a.max_score.total_cmp(&b.max_score));
+        let mut n_non_essential = 0;
+        let mut non_essential_max_score = 0.0;
+        let mut by_order: Vec<usize> = (0..cursors.len()).collect();
+        by_order.sort_unstable_by_key(|&i| cursors[i].order);
+        let mut ranked: Vec<usize> = (0..cursors.len()).collect();
+        // `bounds[i]` bounds the score in the window of a document from the terms of `ranked[..=i]`.
+        let mut bounds = vec![0.0; cursors.len()];
+        let mut matched = [0u64; WINDOW as usize / 64];
+
+        loop {
+            while n_non_essential < cursors.len() && !can_enter(non_essential_max_score + cursors[n_non_essential].max_score, top.threshold()) {
+                non_essential_max_score += cursors[n_non_essential].max_score;
+                n_non_essential += 1;
+            }
+            let Some(window_start) = cursors[n_non_essential..].iter().map(Cursor::doc).min().filter(|&doc| doc != u32::MAX) else {
+                break;
+            };
+            let window_end = window_start.saturating_add(WINDOW).min(segment.n_docs() as u32);
+
+            for cursor in &mut cursors {
+                cursor.window_max_score = cursor.window_max_score(window_start, window_end, scorer, avg_doc_len, absent_tf_weight);
+            }
+            ranked.sort_unstable_by(|&a, &b| cursors[a].window_max_score.total_cmp(&cursors[b].window_max_score));
+            let mut window_bound = 0.0;
+            for (bound, &i) in bounds.iter_mut().zip(&ranked) {
+                window_bound += cursors[i].window_max_score;
+                *bound = window_bound;
+            }
+            let n_window_non_essential = bounds.partition_point(|&bound| !can_enter(bound, top.threshold()));
+
+            // The essential postings of the window are scored together, cursors staying at the
+            // start of the window to look candidates up.
+            for &i in &ranked[n_window_non_essential..] {
+                let cursor = &mut cursors[i];
+                cursor.seek(window_start);
+                let start = cursor.position;
+                let end = start + cursor.doc_indices[start..].partition_point(|&doc| doc < window_end);
+                let (doc_indices, term_frequencies) = (&cursor.doc_indices[start..end], &cursor.term_frequencies[start..end]);
+                scorer.accumulate(cursor.idf, doc_indices, term_frequencies, &segment.doc_lengths, avg_doc_len, scores);
+                for &doc in doc_indices {
+                    let offset = (doc - window_start) as usize;
+                    matched[offset / 64] |= 1 << (offset % 64);
+                }
+            }
+
+            let non_essential_bound = n_window_non_essential.checked_sub(1).map_or(0.0, |i| bounds[i]);
+            let matched_docs = matched.iter_mut().enumerate().flat_map(|(word, bits)| {
+                let bits = std::mem::take(bits);
+                std::iter::successors(Some(bits), |&bits| Some(bits & bits.wrapping_sub(1)))
+                    .take_while(|&bits| bits != 0)
+                    .map(move |bits| window_start + (word * 64) as u32 + bits.trailing_zeros())
+            });
+            for doc in matched_docs {
+                let essential_score = std::mem::take(&mut scores[doc as usize]);
+                if !can_enter(essential_score + non_essential_bound, top.threshold()) {
+                    continue;
+                }
+
+                let doc_len = segment.doc_lengths[doc as usize];
+                let contribution = |idf: f32, tf: f32| idf * (scorer.tf_weight(tf, doc_len, avg_doc_len) - absent_tf_weight);
+
+                let mut bound = essential_score;
+                let mut can_rank = true;
+                for (i, &cursor) in ranked[..n_window_non_essential].iter().enumerate().rev() {
+                    if !can_enter(bound + bounds[i], top.threshold()) {
+                        can_rank = false;
+                        break;
+                    }
+                    let cursor = &mut cursors[cursor];
+                    if let Some(tf) = cursor.seek(doc) {
+                        bound += contribution(cursor.idf, tf);
+                    }
+                }
+
+                let global_doc = doc_offset + doc as usize;
+                if can_rank && can_enter(bound, top.threshold()) && !is_deleted(global_doc) {
+                    // Summed again in query order, for exactly the scores of exhaustive search.
+                    let mut score = 0.0;
+                    for &i in &by_order {
+                        if let Some(tf) = cursors[i].seek(doc) {
+                            score += contribution(cursors[i].idf, tf);
+                        }
+                    }
+                    top.push(global_doc, score);
+                }
+            }
+
+            for cursor in &mut cursors[n_non_essential..] {
+                cursor.seek(window_end);
+            }
+        }
+        doc_offset += segment.n_docs();
+    }
+
+    Some(top)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scoring::{Idf, Method, Scoring};
+    use crate::tokenizer::Corpus;
+
+    /// Xorshift generator, for reproducible collections.
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as usize
+        }
+
+        /// Below `n`, small values being the most likely.
+        fn skewed_below(&mut self, n: usize) -> usize {
+            let bound = 1 + self.below(n);
+            self.below(bound)
+        }
+    }
+
+    const N_TERMS: usize = 64;
+
+    /// Segments of `sizes` documents of 1 to 40 terms, low term ids being the most frequent so that
+    /// their postings span many blocks and windows. A quarter of the documents repeat one of a few
+    /// templates, which makes ties.
+    fn segments(rng: &mut Rng, sizes: &[usize]) -> Vec<Segment> {
+        let document = |rng: &mut Rng| -> Vec<u32> {
+            let len = 1 + rng.below(40);
+            (0..len).map(|_| rng.skewed_below(N_TERMS) as u32).collect()
+        };
+        let templates: Vec<Vec<u32>> = (0..20).map(|_| document(rng)).collect();
+
+        sizes
+            .iter()
+            .map(|&size| {
+                let documents = (0..size)
+                    .map(|_| if rng.below(4) == 0 { templates[rng.below(templates.len())].clone() } else { document(rng) })
+                    .collect();
+                Segment::build(&Corpus { documents, positions: Vec::new() }, N_TERMS, false)
+            })
+            .collect()
+    }
+
+    /// Term-at-a-time scoring of every document, as `Retriever` does without pruning.
+    fn exhaustive(
+        scorer: &dyn Scorer,
+        segments: &[Segment],
+        terms: &[(usize, f32)],
+        avg_doc_len: f32,
+        is_deleted: impl Fn(usize) -> bool,
+        n: usize,
+    ) -> Vec<(usize, f32)> {
+        let mut scored = Vec::new();
+        let mut doc_offset = 0;
+        for segment in segments {
+            let mut scores = vec![0.0; segment.n_docs()];
+            let mut matched = vec![false; segment.n_docs()];
+            for &(term, idf) in terms {
+                let (doc_indices, term_frequencies) = segment.postings.postings(term);
+                scorer.accumulate(idf, doc_indices, term_frequencies, &segment.doc_lengths, avg_doc_len, &mut scores);
+                doc_indices.iter().for_each(|&doc| matched[doc as usize] = true);
+            }
+            scored.extend((0..segment.n_docs()).filter(|&doc| matched[doc]).map(|doc| (doc_offset + doc, scores[doc])));
+            doc_offset += segment.n_docs();
+        }
+
+        scored.retain(|&(doc, _)| !is_deleted(doc));
+        scored.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        scored.truncate(n);
+        scored
+    }
+
+    #[test]
+    fn top_n_matches_exhaustive_search() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        let segments = segments(&mut rng, &[5000, 300, 4500]);
+        let n_docs: usize = segments.iter().map(Segment::n_docs).sum();
+        let avg_doc_len = segments.iter().flat_map(|segment| segment.doc_lengths.iter()).sum::<f32>() / n_docs as f32;
+        let doc_frequency = |term: usize| segments.iter().map(|segment| segment.postings.doc_frequency(term)).sum::<u32>();
+
+        let scorers = [
+            (Method::Atire, false),
+            (Method::Robertson, false),
+            (Method::Bm25L, false),
+            (Method::Bm25L, true),
+            (Method::Bm25Plus, false),
+            (Method::Bm25Plus, true),
+            (Method::Tf, false),
+        ];
+        for (method, exact) in scorers {
+            let scorer = Scoring { method, k1: 1.2, b: 0.75, delta: 0.5, idf: Idf::Lucene, epsilon: 0.25, exact };
+            for deletion_period in [None, Some(11)] {
+                let is_deleted = |doc: usize| deletion_period.is_some_and(|period| doc % period == 3);
+
+                for _ in 0..10 {
+                    let mut query_terms: Vec<usize> = (0..1 + rng.below(6)).map(|_| rng.skewed_below(N_TERMS)).collect();
+                    query_terms.sort_unstable();
+                    query_terms.dedup();
+                    let terms: Vec<(usize, f32)> = query_terms
+                        .iter()
+                        .map(|&term| (term, [1.0, 0.5, 2.0][rng.below(3)] * scorer.idf(doc_frequency(term) as f32, n_docs as f32)))
+                        .collect();
+
+                    for n in [1, 3, 10, 100, n_docs] {
+                        let mut scores = vec![0.0; n_docs];
+                        let top = top_n(&scorer, &segments, &terms, avg_doc_len, is_deleted, n, &mut scores).unwrap();
+                        let expected = exhaustive(&scorer, &segments, &terms, avg_doc_len, is_deleted, n);
+                        assert_eq!(top.into_sorted(), expected, "{method:?} exact={exact} terms={terms:?} n={n}");
+                        assert!(scores.iter().all(|&score| score == 0.0), "scores left in the buffer");
+                    }
+                }
+            }
+        }
+    }
+
+    /// A segment of 3000 documents where term 0 has 2000 postings over 16 blocks, with varying
+    /// frequencies and document lengths, and term 1 a single block of 31 postings.
+    fn blocks_segment() -> Segment {
+        let documents = (0..3000u32)
+            .map(|doc| {
+                let mut terms = Vec::new();
+                if doc % 3 != 1 {
+                    terms.extend(std::iter::repeat_n(0, 1 + (doc as usize * 7) % 5));
+                }
+                if doc % 97 == 0 {
+                    terms.push(1);
+                }
+                terms.extend(std::iter::repeat_n(2, (doc as usize * 13) % 11));
+                terms
+            })
+            .collect();
+        Segment::build(&Corpus { documents, positions: Vec::new() }, 3, false)
+    }
+
+    fn cursor<'a>(segment: &'a Segment, term: usize, scorer: &dyn Scorer, avg_doc_len: f32) -> Cursor<'a> {
+        let (doc_indices, term_frequencies) = segment.postings.postings(term);
+        let idf = 1.5;
+        let max_tf_weight = segment.bounds.max_tf_weight(scorer, term, avg_doc_len).unwrap();
+        Cursor {
+            order: 0,
+            doc_indices,
+            term_frequencies,
+            position: 0,
+            idf,
+            max_score: idf * (max_tf_weight - scorer.absent_tf_weight()),
+            blocks: &segment.bounds.blocks,
+            first_block: segment.bounds.block_offsets[term] as usize,
+            block: 0,
+            window_max_score: 0.0,
+        }
+    }
+
+    #[test]
+    fn seek_finds_the_first_document_at_or_after_the_target() {
+        let segment = blocks_segment();
+        let scorer = Scoring { method: Method::Atire, k1: 1.2, b: 0.75, delta: 0.5, idf: Idf::Lucene, epsilon: 0.25, exact: false };
+        for term in 0..2 {
+            let (doc_indices, term_frequencies) = segment.postings.postings(term);
+            assert!(term != 0 || doc_indices.len() > BLOCK_SIZE * MAX_WINDOW_BLOCKS);
+
+            let starts = [0, 1, 30, 127, 128, 129, 511, 512, 1000, doc_indices.len() - 1, doc_indices.len()];
+            for start in starts.into_iter().filter(|&start| start <= doc_indices.len()) {
+                for target in (0..3002).step_by(7).chain([0, 1, 2, 3000, 3001, u32::MAX - 1]) {
+                    let mut cursor = cursor(&segment, term, &scorer, 10.0);
+                    cursor.position = start;
+                    let found = cursor.seek(target);
+
+                    let expected = start + doc_indices[start..].partition_point(|&doc| doc < target);
+                    assert_eq!(cursor.position, expected, "term {term} from {start} to {target}");
+                    let expected_tf = (doc_indices.get(expected) == Some(&target)).then(|| term_frequencies[expected]);
+                    assert_eq!(found, expected_tf, "term {term} from {start} to {target}");
+                }
+            }
+
+            // Successive seeks, as when looking candidates up.
+            let mut cursor = cursor(&segment, term, &scorer, 10.0);
+            for target in 0..3001 {
+                let found = cursor.seek(target);
+                assert_eq!(found.is_some(), doc_indices.contains(&target), "term {term} to {target}");
+                assert!(cursor.doc() >= target);
+            }
+        }
+    }
+
+    #[test]
+    fn window_max_score_bounds_the_blocks_overlapping_the_window() {
+        let segment = blocks_segment();
+        let avg_doc_len = segment.doc_lengths.iter().sum::<f32>() / segment.n_docs() as f32;
+
+        for exact in [false, true] {
+            let scorer = Scoring { method: Method::Bm25Plus, k1: 1.2, b: 0.75, delta: 0.5, idf: Idf::Lucene, epsilon: 0.25, exact };
+            let absent_tf_weight = scorer.absent_tf_weight();
+            for term in 0..2 {
+                let (doc_indices, term_frequencies) = segment.postings.postings(term);
+                let n_blocks = doc_indices.len().div_ceil(BLOCK_SIZE);
+                let block_postings = |block: usize| block * BLOCK_SIZE..((block + 1) * BLOCK_SIZE).min(doc_indices.len());
+                let block_bound = |block: usize| {
+                    let max_tf_weight = block_postings(block)
+                        .map(|posting| {
+                            let doc_len = segment.doc_lengths[doc_indices[posting] as usize];
+                            scorer.tf_weight(term_frequencies[posting], doc_len, avg_doc_len)
+                        })
+                        .fold(f32::NEG_INFINITY, f32::max);
+                    1.5 * (max_tf_weight - absent_tf_weight)
+                };
+
+                // Windows starting on both sides of each block edge, and past the last posting.
+                let edges = (1..n_blocks).map(|block| doc_indices[block * BLOCK_SIZE]);
+                let starts: Vec<u32> = edges.flat_map(|doc| [doc - 2, doc - 1, doc, doc + 1]).chain([0, 2999, 3000]).collect();
+                for start in starts {
+                    for width in [1, 2, 50, 200, 700, 1500, WINDOW] {
+                        let end = start.saturating_add(width).min(segment.n_docs() as u32);
+                        let mut cursor = cursor(&segment, term, &scorer, avg_doc_len);
+                        cursor.seek(start);
+                        let bound = cursor.window_max_score(start, end, &scorer, avg_doc_len, absent_tf_weight);
+
+                        let overlapping: Vec<usize> = (0..n_blocks)
+                            .filter(|&block| doc_indices[block_postings(block).start] < end && doc_indices[block_postings(block).end - 1] >= start)
+                            .collect();
+                        let expected = if overlapping.len() > MAX_WINDOW_BLOCKS {
+                            cursor.max_score
+                        } else {
+                            overlapping.iter().map(|&block| block_bound(block)).fold(0.0, f32::max)
+                        };
+                        assert_eq!(bound, expected, "term {term} exact={exact} window {start}..{end}");
+
+                        let window_postings = doc_indices.iter().zip(term_frequencies).filter(|(&doc, _)| (start..end).contains(&doc));
+                        for (&doc, &tf) in window_postings {
+                            let score = 1.5 * (scorer.tf_weight(tf, segment.doc_lengths[doc as usize], avg_doc_len) - absent_tf_weight);
+                            assert!(score <= bound, "term {term} exact={exact} window {start}..{end} doc {doc}");
+                        }
+                    }
+                }
+
+                // Consecutive windows with a single cursor, as `top_n` walks them.
+                for width in [100, 300, WINDOW] {
+                    let mut walking = cursor(&segment, term, &scorer, avg_doc_len);
+                    for start in (0..3000).step_by(width as usize) {
+                        let end = (start + width).min(3000);
+                        let bound = walking.window_max_score(start, end, &scorer, avg_doc_len, absent_tf_weight);
+                        let fresh_bound = cursor(&segment, term, &scorer, avg_doc_len).window_max_score(start, end, &scorer, avg_doc_len, absent_tf_weight);
+                        assert_eq!(bound, fresh_bound, "term {term} exact={exact} window {start}..{end}");
+                        walking.seek(end);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,259 @@
+use std::collections::HashMap;
+use std::io;
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use crate::persistence::{invalid_data, Reader, Writer};
+use crate::tokenizer::{PositionedTokens, Token};
+
+/// Query tokens, each with the weight it was given.
+pub type WeightedTokens = Vec<(String, f32)>;
+
+/// Whether the documents returned must, may or must not match a clause.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Occur {
+    Should,
+    Must,
+    MustNot,
+}
+
+/// Tokens that must appear in this order, each at most `slop` positions away from
+/// where the exact phrase would put it. `positions` are those of the tokens in the phrase text,
+/// which leave gaps where it had stopwords.
+pub struct Phrase {
+    pub tokens: Vec<String>,
+    pub positions: Vec<u32>,
+    pub slop: u32,
+    pub weight: f32,
+    pub occur: Occur,
+}
+
+/// How a pattern matches indexed terms.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PatternKind {
+    /// `*` and `?` wildcards, see `TermDictionary::matching`.
+    Wildcard,
+    /// Terms at most `max_distance` edits away, see `TermDictionary::fuzzy`.
+    Fuzzy { max_distance: u32 },
+}
+
+/// A word standing for the indexed terms it matches. Wildcard patterns are not tokenized, so they
+/// match the terms as they were indexed, stemmed or not; fuzzy patterns are query tokens.
+pub struct Pattern {
+    pub text: String,
+    pub kind: PatternKind,
+    pub weight: f32,
+    pub occur: Occur,
+}
+
+/// A tokenized query. Documents are scored on `terms`, `phrases` and `patterns`, and only those
+/// containing every `required` token and no `prohibited` one are ranked. Required tokens are also in `terms`.
+#[derive(Default)]
+pub struct Query {
+    pub terms: WeightedTokens,
+    pub required: Vec<String>,
+    pub prohibited: Vec<String>,
+    pub phrases: Vec<Phrase>,
+    pub patterns: Vec<Pattern>,
+}
+
+impl Query {
+    /// Parses `text` as whitespace separated clauses: words prefixed with `+` are required,
+    /// those prefixed with `-` are prohibited, and the others optional. Each clause is tokenized,
+    /// a required clause yielding several tokens requiring all of them. Quoted clauses are phrases,
+    /// optionally followed by `~` and a slop, as in `+"heart attack"~2`; they can be prefixed too.
+    /// Words containing `*` or `?` are patterns, a required one being matched by any of its terms.
+    /// Words followed by `~` and an edit distance of 1 or 2, as in `vacine~1`, match the indexed terms
+    /// that close to their tokens; a missing distance means 2.
+    fn parse(&mut self, text: &str, weight: f32, tokenize: &impl Fn(&str) -> PyResult<PositionedTokens>) -> PyResult<()> {
+        let mut optional = Vec::new();
+        let mut rest = text.trim_start();
+
+        while !rest.is_empty() {
+            let (occur, clause) = match rest.as_bytes()[0] {
+                b'+' => (Occur::Must, &rest[1..]),
+                b'-' => (Occur::MustNot, &rest[1..]),
+                _ => (Occur::Should, rest),
+            };
+
+            if let Some(quoted) = clause.strip_prefix('"') {
+                let end = quoted.find('"').unwrap_or(quoted.len());
+                rest = quoted.get(end + 1..).unwrap_or("");
+
+                let mut slop = 0;
+                if let Some(suffix) = rest.strip_prefix('~') {
+                    let digits = suffix.find(|c: char| !c.is_ascii_digit()).unwrap_or(suffix.len());
+                    slop = suffix[..digits].parse().unwrap_or(0);
+                    rest = &suffix[digits..];
+                }
+                self.add_phrase(tokenize(&quoted[..end])?, slop, weight, occur);
+            } else {
+                let end = clause.find(char::is_whitespace).unwrap_or(clause.len());
+                let word = &clause[..end];
+                rest = &clause[end..];
+
+                let fuzzy = word.rsplit_once('~').filter(|(_, distance)| distance.chars().all(|c| c.is_ascii_digit()));
+                if word.contains(['*', '?']) {
+                    self.patterns.push(Pattern { text: word.to_string(), kind: PatternKind::Wildcard, weight, occur });
+                } else if let Some((word, distance)) = fuzzy {
+                    let max_distance = match distance {
+                        "" => 2,
+                        _ => distance.parse().ok().filter(|distance| (1..=2).contains(distance))
+                            .ok_or_else(|| PyValueError::new_err(format!("invalid edit distance in {:?}, expected 1 or 2", &clause[..end])))?,
+                    };
+                    for (token, _) in tokenize(word)? {
+                        self.patterns.push(Pattern { text: token, kind: PatternKind::Fuzzy { max_distance }, weight, occur });
+                    }
+                } else if occur == Occur::Should {
+                    optional.push(word);
+                } else {
+                    self.add_terms(tokenize(word)?, weight, occur);
+                }
+            }
+            rest = rest.trim_start();
+        }
+
+        // Optional words are tokenized together, so that custom tokenizers see them in context.
+        self.add_terms(tokenize(&optional.join(" "))?, weight, Occur::Should);
+        Ok(())
+    }
+
+    fn add_terms(&mut self, tokens: PositionedTokens, weight: f32, occur: Occur) {
+        let tokens = tokens.into_iter().map(|(token, _)| token);
+        match occur {
+            Occur::Should => self.terms.extend(tokens.into_iter().map(|token| (token, weight))),
+            Occur::Must => {
+                for token in tokens {
+                    self.required.push(token.clone());
+                    self.terms.push((token, weight));
+                }
+            }
+            Occur::MustNot => self.prohibited.extend(tokens),
+        }
+    }
+
+    /// Phrases of a single token are plain terms.
+    fn add_phrase(&mut self, tokens: PositionedTokens, slop: u32, weight: f32, occur: Occur) {
+        if tokens.len() < 2 {
+            self.add_terms(tokens, weight, occur);
+        } else {
+            let (tokens, positions) = tokens.into_iter().unzip();
+            self.phrases.push(Phrase { tokens, positions, slop, weight, occur });
+        }
+    }
+}
+
+impl From<WeightedTokens> for Query {
+    fn from(terms: WeightedTokens) -> Self {
+        Self { terms, ..Default::default() }
+    }
+}
+
+/// A query as passed to `top_n`: a text, or a dict mapping texts to weights, for instance
+/// to expand a query with weaker related terms. Tokens of a weighted text share its weight.
+#[derive(FromPyObject)]
+pub enum TextQuery {
+    Weighted(HashMap<String, f32>),
+    Text(String),
+}
+
+impl TextQuery {
+    /// With `parse`, texts are read as boolean queries, see `Query::parse`.
+    pub fn tokenize(self, parse: bool, tokenize: impl Fn(&str) -> PyResult<PositionedTokens>) -> PyResult<Query> {
+        let texts = match self {
+            TextQuery::Weighted(texts) => texts.into_iter().collect(),
+            TextQuery::Text(text) => vec![(text, 1.0)],
+        };
+
+        let mut query = Query::default();
+        for (text, weight) in texts {
+            if parse {
+                query.parse(&text, weight, &tokenize)?;
+            } else {
+                query.terms.extend(tokenize(&text)?.into_iter().map(|(token, _)| (token, weight)));
+            }
+        }
+        Ok(query)
+    }
+}
+
+/// A query as passed to `top_n_tokens`: a list of tokens, or a dict mapping tokens to weights.
+#[derive(FromPyObject)]
+pub enum TokenQuery {
+    Weighted(HashMap<Token, f32>),
+    Tokens(Vec<Token>),
+}
+
+impl From<TokenQuery> for Query {
+    fn from(query: TokenQuery) -> Self {
+        let terms: WeightedTokens = match query {
+            TokenQuery::Weighted(tokens) => tokens.into_iter().map(|(token, weight)| (token.into(), weight)).collect(),
+            TokenQuery::Tokens(tokens) => tokens.into_iter().map(|token| (token.into(), 1.0)).collect(),
+        };
+        terms.into()
+    }
+}
+
+/// How the weights of a term repeated in a query combine.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum QueryTf {
+    /// Repetitions are ignored, the term keeps its largest weight.
+    Ignore,
+    /// Weights add up, a term repeated twice counting twice.
+    Count,
+    /// Weights add up to a query frequency `qtf`, saturated as `qtf * (k3 + 1) / (k3 + qtf)`.
+    Saturate { k3: f32 },
+}
+
+impl QueryTf {
+    pub fn from_name(name: &str, k3: f32) -> Option<QueryTf> {
+        match name {
+            "ignore" => Some(QueryTf::Ignore),
+            "count" => Some(QueryTf::Count),
+            "saturate" => Some(QueryTf::Saturate { k3 }),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            QueryTf::Ignore => "ignore",
+            QueryTf::Count => "count",
+            QueryTf::Saturate { .. } => "saturate",
+        }
+    }
+
+    fn combine(&self, weights: impl Iterator<Item = f32>) -> f32 {
+        match self {
+            QueryTf::Ignore => weights.fold(f32::NEG_INFINITY, f32::max),
+            QueryTf::Count => weights.sum(),
+            QueryTf::Saturate { k3 } => {
+                let qtf: f32 = weights.sum();
+                qtf * (k3 + 1.0) / (k3 + qtf)
+            }
+        }
+    }
+
+    /// Combines the weights of repeated terms, sorting terms by id to improve the cache access pattern.
+    pub fn term_weights(&self, mut terms: Vec<(usize, f32)>) -> Vec<(usize, f32)> {
+        terms.sort_unstable_by_key(|&(term, _)| term);
+
+        terms
+            .chunk_by(|a, b| a.0 == b.0)
+            .map(|occurrences| (occurrences[0].0, self.combine(occurrences.iter().map(|&(_, weight)| weight))))
+            .collect()
+    }
+
+    pub fn write(&self, writer: &mut Writer) -> io::Result<()> {
+        writer.write_str(self.name())?;
+        writer.write_f32(match self {
+            QueryTf::Saturate { k3 } => *k3,
+            _ => 0.0,
+        })
+    }
+
+    pub fn read(reader: &mut Reader) -> io::Result<QueryTf> {
+        let name = reader.read_str()?;
+        let k3 = reader.read_f32()?;
+        QueryTf::from_name(&name, k3).ok_or_else(|| invalid_data(&format!("unknown query tf {name:?}")))
+    }
+}
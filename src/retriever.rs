@@ -1,256 +1,1318 @@
+use std::borrow::Cow;
 use std::cell::RefCell;
-use std::collections::HashMap;
-use sprs::TriMatI;
-use ndarray::{s, Array1, Axis};
+use std::io;
+use std::sync::OnceLock;
+use std::ops::Range;
+use std::path::PathBuf;
+use pyo3::exceptions::{PyKeyError, PyValueError};
 use pyo3::prelude::*;
 use pyo3::{pyclass, pymethods};
-use pyo3::types::{PyList};
+use pyo3::types::{PyDict, PyList, PyString, PyTuple};
 use rayon::prelude::*;
-use crate::tokenizer::{Corpus, TokenizeOutput, Tokenizer, Vocab};
+use crate::dictionary::TermDictionary;
+use crate::documents::{DocId, Documents};
+use crate::fields::{Field, FieldSelection};
+use crate::persistence::{invalid_data, Reader, Writer};
+use crate::positions::phrase_frequency;
+use crate::pruning::{self, TopK};
+use crate::query::{Occur, Pattern, PatternKind, Phrase, Query, QueryTf, TextQuery, TokenQuery};
+use crate::scoring::{Idf, Method, Scorer, Scoring};
+use crate::segment::Segment;
+use crate::tokenizer::{stemmer_algorithm, Corpus, StopWords, Token, Tokenizer, TokenizerConfig, Vocab};
 use thread_local::ThreadLocal;
 
-type DocFrequencies = HashMap<u32, u32>;
-type TermFrequencies = Vec<(Array1<u32>, Array1<f32>)>;
-type IdfArray = Array1<f32>;
+type SearchResult = Vec<(DocId, f32)>;
 
-struct Frequencies {
-    doc_frequencies: DocFrequencies,
-    term_frequencies: TermFrequencies,
+/// Collection-wide values derived from the postings, computed on first use and dropped
+/// whenever documents are added or compacted.
+#[derive(Default)]
+struct StatisticsCache {
+    average_idf: OnceLock<f32>,
+    inverse_doc_norms: OnceLock<Vec<f32>>,
+    average_field_lengths: OnceLock<Vec<f32>>,
 }
 
-struct MatrixParams {
-    rows: Array1<u32>,
-    cols: Array1<u32>,
-    scores: Array1<f32>,
+/// Query-time settings shared by the queries of a search call.
+struct SearchOptions {
+    /// Weight of each field, see `FieldSelection`.
+    field_weights: Vec<f32>,
+    /// Maximum number of terms a pattern expands to.
+    max_expansions: usize,
+    /// Edit distance within which query terms missing from the index match indexed terms, 0 to drop them.
+    fuzziness: u32,
+    /// Number of leading characters fuzzy matches must share with the query term.
+    prefix_length: usize,
 }
 
-struct MatrixComponents{
-    indices: Vec<u32>,
-    values: Vec<f32>,
-    indptr: Vec<u32>,
+const MAX_EXPANSIONS: usize = 50;
+
+/// Per-thread scratch space of a query, sized to the number of documents. `scores`, `field_tfs`
+/// and `required_matches` are left zeroed by each query, so that the next one only resets what it sets.
+#[derive(Default)]
+struct ScoreBuffer {
+    scores: Vec<f32>,
+    /// BM25F pseudo term frequencies of the current term, see `Field`.
+    field_tfs: Vec<f32>,
+    required_matches: RequiredMatches,
+    matches: Matches,
+}
+
+/// The documents a query gave a score to. They are listed as they are inserted, unless the
+/// postings of the query cover a large share of the index: they are then only flagged, which
+/// avoids checking each posting for an earlier one of its document, and walked in order of index.
+#[derive(Default)]
+struct Matches {
+    docs: Vec<usize>,
+    /// One bit per document, set for the inserted ones.
+    bits: Vec<u64>,
+    is_dense: bool,
+}
+
+/// Share of the index, as a divisor, from which the postings of a query are considered dense.
+const DENSE_MATCHES: usize = 16;
+
+impl Matches {
+    /// Prepares for a query with `n_postings` postings in an index of `n_docs` documents.
+    fn start(&mut self, n_docs: usize, n_postings: usize) {
+        self.bits.resize(n_docs.div_ceil(64), 0);
+        self.is_dense = n_postings >= n_docs / DENSE_MATCHES;
+    }
+
+    fn contains(&self, doc: usize) -> bool {
+        self.bits[doc / 64] & (1 << (doc % 64)) != 0
+    }
+
+    fn insert(&mut self, doc: usize) {
+        let (word, bit) = (&mut self.bits[doc / 64], 1 << (doc % 64));
+        if *word & bit == 0 {
+            *word |= bit;
+            if !self.is_dense {
+                self.docs.push(doc);
+            }
+        }
+    }
+
+    /// Inserts the documents of a segment whose first document is `doc_offset`.
+    fn extend(&mut self, doc_offset: usize, doc_indices: &[u32]) {
+        if self.is_dense {
+            for &doc in doc_indices {
+                let doc = doc_offset + doc as usize;
+                self.bits[doc / 64] |= 1 << (doc % 64);
+            }
+        } else {
+            for &doc in doc_indices {
+                self.insert(doc_offset + doc as usize);
+            }
+        }
+    }
+
+    /// Calls `f` with each document once.
+    fn for_each(&self, mut f: impl FnMut(usize)) {
+        if !self.is_dense {
+            self.docs.iter().for_each(|&doc| f(doc));
+            return;
+        }
+        for (i, &word) in self.bits.iter().enumerate() {
+            let mut bits = word;
+            while bits != 0 {
+                f(i * 64 + bits.trailing_zeros() as usize);
+                bits &= bits - 1;
+            }
+        }
+    }
+
+    /// Forgets the documents, zeroing their entries of `scores`.
+    fn clear(&mut self, scores: &mut [f32]) {
+        if self.is_dense {
+            self.bits.fill(0);
+            scores.fill(0.0);
+        }
+        for doc in self.docs.drain(..) {
+            self.bits[doc / 64] = 0;
+            scores[doc] = 0.0;
+        }
+    }
+}
+
+const PROHIBITED: u32 = u32::MAX;
+
+/// Number of required clauses each document matches, `PROHIBITED` if it matches a prohibited one,
+/// with the documents whose count is not zero.
+#[derive(Default)]
+struct RequiredMatches {
+    counts: Vec<u32>,
+    docs: Vec<usize>,
 }
 
-type SearchResult = Vec<(usize, f32)>;
+impl RequiredMatches {
+    fn start(&mut self, n_docs: usize) {
+        self.counts.resize(n_docs, 0);
+    }
+
+    fn get(&self, doc: usize) -> u32 {
+        self.counts[doc]
+    }
+
+    /// Counts a match of the clause of index `clause`, if `doc` matched all the previous ones.
+    fn count(&mut self, doc: usize, clause: u32) {
+        if self.counts[doc] == clause {
+            if clause == 0 {
+                self.docs.push(doc);
+            }
+            self.counts[doc] += 1;
+        }
+    }
+
+    fn prohibit(&mut self, doc: usize) {
+        if self.counts[doc] == 0 {
+            self.docs.push(doc);
+        }
+        self.counts[doc] = PROHIBITED;
+    }
+
+    /// Zeroes the counts of the documents.
+    fn clear(&mut self) {
+        for doc in self.docs.drain(..) {
+            self.counts[doc] = 0;
+        }
+    }
+}
 
 #[pyclass]
 pub struct Retriever {
-    k1: f32,
-    b: f32,
+    scorer: Box<dyn Scorer>,
     tokenizer: Tokenizer,
+    fields: Vec<Field>,
+    query_tf: QueryTf,
+    positions: bool,
     vocab: Vocab,
+    /// The terms of `vocab` in the documents indexed, sorted for pattern and fuzzy queries.
+    dictionary: TermDictionary,
     n_docs: usize,
-    matrix_components: MatrixComponents,
-    score_buffer: ThreadLocal<RefCell<Vec<f32>>>,
+    total_doc_length: f64,
+    segments: Vec<Segment>,
+    documents: Documents,
+    statistics: StatisticsCache,
+    score_buffer: ThreadLocal<RefCell<ScoreBuffer>>,
 }
 
 #[pymethods]
 impl Retriever {
+    /// `stopwords` is a language name such as `"french"`, an iterable of words, or `None` to keep every token.
+    /// `stemmer` is the language of a Snowball stemmer, or `None` to index tokens unstemmed.
+    /// `token_pattern` is the regex matching tokens in lowercased text, it defaults to words of two characters or more.
+    /// `tokenizer` is a callable mapping a text to a list of tokens, replacing `token_pattern` for both documents and
+    /// queries; stopwords, matched regardless of case, and stemming are still applied to its output unless disabled.
+    /// `method` is one of `"robertson"`, `"atire"`, `"lucene"`, `"bm25l"` and `"bm25+"`, the last two using `delta`,
+    /// or a baseline ignoring `k1` and `b`: `"tfidf"` (log TF times IDF, cosine-normalized) or `"tf"` (raw counts).
+    /// `idf` overrides the IDF of the method with `"lucene"`, `"robertson"` (negative values floored to `epsilon`
+    /// times the average IDF) or `"smooth"`; the first and last never give a zero weight to a term.
+    /// `exact=True` scores BM25L and BM25+ exactly: documents missing a query term still get its shift.
+    /// `fields` maps field names to `(weight, b)` pairs, documents then being dicts of fields scored with BM25F,
+    /// which replaces `b` by the one of each field.
+    /// `query_tf` decides how a term repeated in a query counts: `"count"` adds up its occurrences, `"ignore"`
+    /// counts it once, and `"saturate"` dampens repetitions with `k3` like `k1` dampens those in documents.
+    /// `positions=True` also indexes token positions, which phrase queries need.
     #[new]
-    pub fn new(k1: f32, b: f32) -> Self {
-        Self {
-            k1,
-            b,
-            tokenizer: Tokenizer::new(),
+    #[pyo3(signature = (
+        k1,
+        b,
+        stopwords=Some(StopWords::Language("english".to_string())),
+        stemmer=Some("english".to_string()),
+        token_pattern=None,
+        tokenizer=None,
+        method="atire",
+        delta=0.5,
+        idf=None,
+        epsilon=0.25,
+        exact=false,
+        fields=None,
+        query_tf="count",
+        k3=8.0,
+        positions=false,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        k1: f32,
+        b: f32,
+        stopwords: Option<StopWords>,
+        stemmer: Option<String>,
+        token_pattern: Option<String>,
+        tokenizer: Option<Py<PyAny>>,
+        method: &str,
+        delta: f32,
+        idf: Option<&str>,
+        epsilon: f32,
+        exact: bool,
+        fields: Option<Bound<'_, PyDict>>,
+        query_tf: &str,
+        k3: f32,
+        positions: bool,
+    ) -> PyResult<Self> {
+        let method = Method::from_name(&method.to_lowercase())
+            .ok_or_else(|| PyValueError::new_err(format!("unknown method {method:?}")))?;
+        let idf = idf
+            .map(|name| Idf::from_name(&name.to_lowercase()).ok_or_else(|| PyValueError::new_err(format!("unknown idf {name:?}"))))
+            .transpose()?
+            .unwrap_or(Idf::Method);
+        let query_tf = QueryTf::from_name(&query_tf.to_lowercase(), k3)
+            .ok_or_else(|| PyValueError::new_err(format!("unknown query_tf {query_tf:?}")))?;
+
+        let stemmer = stemmer
+            .map(|name| stemmer_algorithm(&name.to_lowercase()).ok_or_else(|| PyValueError::new_err(format!("no stemmer available for language {name:?}"))))
+            .transpose()?;
+
+        let default_config = TokenizerConfig::default();
+        let config = TokenizerConfig {
+            pattern: token_pattern.unwrap_or(default_config.pattern),
+            stop_words: stopwords.map_or(Ok(Vec::new()), StopWords::resolve).map_err(PyValueError::new_err)?,
+            stemmer,
+            custom_splitter: tokenizer.is_some(),
+        };
+        let tokenizer = Tokenizer::from_config(config, tokenizer)
+            .map_err(|e| PyValueError::new_err(format!("invalid token_pattern: {e}")))?;
+
+        Ok(Self {
+            scorer: Box::new(Scoring { method, k1, b, delta, idf, epsilon, exact }),
+            tokenizer,
+            fields: fields.as_ref().map(Field::extract_all).transpose()?.unwrap_or_default(),
+            query_tf,
+            positions,
             vocab: Default::default(),
+            dictionary: TermDictionary::default(),
             n_docs: 0,
-            matrix_components: MatrixComponents {indices:Default::default(), values:Default::default(), indptr:Default::default()},
+            total_doc_length: 0.0,
+            segments: Vec::new(),
+            documents: Documents::default(),
+            statistics: StatisticsCache::default(),
             score_buffer: ThreadLocal::default(),
+        })
+    }
+
+    /// `documents` is a list of texts, a list of `(id, text)` pairs or a dict mapping ids to texts.
+    /// Documents indexed with a string id are returned by that id, others by their position.
+    /// With `fields`, texts are replaced by dicts mapping field names to texts, missing fields being empty.
+    pub fn index<'py>(&mut self, documents: &Bound<'py, PyAny>) -> PyResult<()> {
+        self.add_documents(documents, false, true)
+    }
+
+    /// Indexes `documents` as a new segment after the existing documents, which keep their ids.
+    pub fn add<'py>(&mut self, documents: &Bound<'py, PyAny>) -> PyResult<()> {
+        self.add_documents(documents, false, false)
+    }
+
+    /// Like `index`, but each document is a list of tokens that is indexed as is,
+    /// bypassing the token pattern, stopword removal and stemming. Tokens are strings,
+    /// or integer ids, such as those of a subword tokenizer, which stand for their decimal text.
+    pub fn index_tokens<'py>(&mut self, documents: &Bound<'py, PyAny>) -> PyResult<()> {
+        self.add_documents(documents, true, true)
+    }
+
+    pub fn add_tokens<'py>(&mut self, documents: &Bound<'py, PyAny>) -> PyResult<()> {
+        self.add_documents(documents, true, false)
+    }
+
+    /// Deleted documents stop being returned immediately, but keep counting towards
+    /// collection statistics until `compact` is called, as their postings are still stored.
+    pub fn delete(&mut self, doc_ids: Vec<DocId>) -> PyResult<()> {
+        let positions = doc_ids
+            .into_iter()
+            .map(|doc_id| self.documents.position(&doc_id).ok_or_else(|| PyKeyError::new_err(doc_id)))
+            .collect::<PyResult<Vec<_>>>()?;
+
+        for position in positions {
+            self.documents.delete(position);
         }
+        Ok(())
+    }
+
+    /// Replaces the text, or dict of fields, of `doc_id`, which keeps its id.
+    pub fn update(&mut self, doc_id: DocId, document: &Bound<'_, PyAny>) -> PyResult<()> {
+        self.update_document(doc_id, document, false)
+    }
+
+    /// Like `update`, but the document is a list of tokens that is indexed as is, see `index_tokens`.
+    pub fn update_tokens(&mut self, doc_id: DocId, document: &Bound<'_, PyAny>) -> PyResult<()> {
+        self.update_document(doc_id, document, true)
     }
 
-    pub fn index<'py>(&mut self, texts: &Bound<'py, PyList>) {
-        self.internal_index(texts);
+    /// Purges the postings of deleted documents, and the terms only they contained, and merges all
+    /// segments into one, so that document frequencies and lengths only reflect live documents again.
+    pub fn compact(&mut self) {
+        let remap = self.documents.compact();
+        let (segment, term_remap) = Segment::merge(&self.segments, &remap, self.vocab.len());
+        self.vocab.retain(|_, term| term_remap[*term as usize].map(|new_term| *term = new_term).is_some());
+        self.dictionary = TermDictionary::build(&self.vocab);
+
+        self.n_docs = segment.n_docs();
+        self.total_doc_length = segment.doc_lengths.iter().map(|&len| len as f64).sum();
+        self.segments = if segment.n_docs() == 0 { Vec::new() } else { vec![segment] };
+        self.statistics = StatisticsCache::default();
     }
 
     pub fn mat_mem(&self) -> f64 {
-        let indices_mem = self.matrix_components.indices.len() * size_of::<u32>();
-        let values_mem = self.matrix_components.values.len() * size_of::<f32>();
-        let indptr_mem = self.matrix_components.indptr.len() * size_of::<u32>();
-        (indices_mem + values_mem + indptr_mem) as f64 /  1024.0 / 1024.0
+        let mem: usize = self.segments.iter().map(Segment::mem).sum();
+        mem as f64 / 1024.0 / 1024.0
+    }
+
+    /// Indexes built with a custom Rust scorer can only be loaded with `load_with_scorer`.
+    pub fn save(&self, path: PathBuf) -> PyResult<()> {
+        let mut writer = Writer::create(&path)?;
+        self.write_index(&mut writer)?;
+        writer.finish()?;
+        Ok(())
+    }
+
+    /// With `mmap=True` the postings are read in place from the file instead of being copied,
+    /// so processes loading the same file share a single page-cache copy of the index.
+    /// `verify=False` skips the checksum and posting bounds checks, which with `mmap=True` would read the whole file.
+    /// Indexes built with a custom `tokenizer` callable need it passed again.
+    #[staticmethod]
+    #[pyo3(signature = (path, mmap=false, verify=true, tokenizer=None))]
+    pub fn load(path: PathBuf, mmap: bool, verify: bool, tokenizer: Option<Py<PyAny>>) -> PyResult<Self> {
+        let mut reader = if mmap { Reader::map(&path, verify)? } else { Reader::open(&path, verify)? };
+        Retriever::read_index(&mut reader, verify, tokenizer, None)
+    }
+
+    /// `query` is a text, or a dict mapping texts to weights, whose tokens share the weight of their text.
+    /// `fields` restricts the search to a list of fields, or maps the fields to search to boosts
+    /// multiplying their weights. Documents only matching in other fields are not scored.
+    /// With `parse=True`, words prefixed with `+` must be in the documents returned and words prefixed
+    /// with `-` must not, whatever their field; for instance `"+covid -influenza vaccine"`. Quoted words
+    /// are phrases, scored on how often they occur; `"heart attack"~2` lets their words be up to two
+    /// positions away from where the exact phrase would put them. Words with wildcards, such as `immun*`
+    /// or `h?art`, match the indexed terms they expand to, stemmed or not: at most `max_expansions` terms,
+    /// those in the most documents first. Words followed by `~1` or `~2`, such as `vacine~1`, match the
+    /// indexed terms within that edit distance, weighted by their similarity `1 - distance / min(len)`.
+    /// `fuzziness`, 1 or 2, does the same for every query term missing from the index instead of dropping
+    /// it. Fuzzy matches share the first `prefix_length` characters of the term, and at most
+    /// `max_expansions` are kept, the most similar first.
+    #[pyo3(signature = (query, n, fields=None, parse=false, max_expansions=MAX_EXPANSIONS, fuzziness=0, prefix_length=0))]
+    #[allow(clippy::too_many_arguments)]
+    pub fn top_n(
+        &self,
+        py: Python<'_>,
+        query: TextQuery,
+        n: usize,
+        fields: Option<FieldSelection>,
+        parse: bool,
+        max_expansions: usize,
+        fuzziness: u32,
+        prefix_length: usize,
+    ) -> PyResult<SearchResult> {
+        let options = self.search_options(fields, max_expansions, fuzziness, prefix_length)?;
+        let tokenized_query = query.tokenize(parse, |text| self.tokenizer.perform_simple(py, text))?;
+        self.check_phrases(&tokenized_query)?;
+        Ok(self.internal_top_n(&tokenized_query, n, &options))
     }
 
-    pub fn top_n(&self, query: String, n: usize) -> SearchResult {
-        self.internal_top_n(&query, n)
+    /// Searches with query tokens used as is, for indexes built with `index_tokens`.
+    /// `query` is a list of tokens, or a dict mapping tokens to weights, tokens being strings or integer ids
+    /// as in `index_tokens`. See `top_n` for the other arguments.
+    #[pyo3(signature = (query, n, fields=None, max_expansions=MAX_EXPANSIONS, fuzziness=0, prefix_length=0))]
+    pub fn top_n_tokens(
+        &self,
+        query: TokenQuery,
+        n: usize,
+        fields: Option<FieldSelection>,
+        max_expansions: usize,
+        fuzziness: u32,
+        prefix_length: usize,
+    ) -> PyResult<SearchResult> {
+        let options = self.search_options(fields, max_expansions, fuzziness, prefix_length)?;
+        Ok(self.internal_top_n(&Query::from(query), n, &options))
+    }
+
+    #[pyo3(signature = (queries, n, fields=None, max_expansions=MAX_EXPANSIONS, fuzziness=0, prefix_length=0))]
+    pub fn top_n_tokens_batched(
+        &self,
+        queries: Vec<TokenQuery>,
+        n: usize,
+        fields: Option<FieldSelection>,
+        max_expansions: usize,
+        fuzziness: u32,
+        prefix_length: usize,
+    ) -> PyResult<Vec<SearchResult>> {
+        let options = self.search_options(fields, max_expansions, fuzziness, prefix_length)?;
+        Ok(queries
+            .into_par_iter()
+            .map(|query| self.internal_top_n(&Query::from(query), n, &options))
+            .collect())
     }
 
-    pub fn top_n_batched(&self, queries: Vec<String>, n: usize) -> Vec<SearchResult> {
-        queries
+    #[pyo3(signature = (queries, n, fields=None, parse=false, max_expansions=MAX_EXPANSIONS, fuzziness=0, prefix_length=0))]
+    #[allow(clippy::too_many_arguments)]
+    pub fn top_n_batched(
+        &self,
+        py: Python<'_>,
+        queries: Vec<TextQuery>,
+        n: usize,
+        fields: Option<FieldSelection>,
+        parse: bool,
+        max_expansions: usize,
+        fuzziness: u32,
+        prefix_length: usize,
+    ) -> PyResult<Vec<SearchResult>> {
+        let options = self.search_options(fields, max_expansions, fuzziness, prefix_length)?;
+        let tokenized_queries = if self.tokenizer.calls_python() {
+            queries
+                .into_iter()
+                .map(|query| query.tokenize(parse, |text| self.tokenizer.perform_simple(py, text)))
+                .collect::<PyResult<Vec<_>>>()?
+        } else {
+            queries
+                .into_par_iter()
+                .map(|query| query.tokenize(parse, |text| Ok(self.tokenizer.perform_simple_native(text))))
+                .collect::<PyResult<Vec<_>>>()?
+        };
+        for tokenized_query in &tokenized_queries {
+            self.check_phrases(tokenized_query)?;
+        }
+
+        Ok(tokenized_queries
             .par_iter()
-            .map(|query| self.internal_top_n(&query, n))
-            .collect()
+            .map(|tokenized_query| self.internal_top_n(tokenized_query, n, &options))
+            .collect())
     }
 }
 
 impl Retriever {
-    fn internal_index<'py>(&mut self, texts: &Bound<'py, PyList>) {
-        let TokenizeOutput {corpus, vocab} =  self.tokenizer.perform(texts);
-        self.vocab = vocab;
+    /// An empty retriever weighting terms with `scorer`, for Rust crates using their own weighting.
+    pub fn with_scorer(scorer: impl Scorer + 'static, tokenizer: Tokenizer) -> Retriever {
+        Self {
+            scorer: Box::new(scorer),
+            tokenizer,
+            fields: Vec::new(),
+            query_tf: QueryTf::Count,
+            positions: false,
+            vocab: Default::default(),
+            dictionary: TermDictionary::default(),
+            n_docs: 0,
+            total_doc_length: 0.0,
+            segments: Vec::new(),
+            documents: Documents::default(),
+            statistics: StatisticsCache::default(),
+            score_buffer: ThreadLocal::default(),
+        }
+    }
 
-        let Frequencies { doc_frequencies, term_frequencies } = Retriever::compute_frequencies(&corpus);
+    /// Loads an index saved with a custom scorer, which has to be provided again like a custom tokenizer.
+    pub fn load_with_scorer(
+        path: PathBuf,
+        mmap: bool,
+        verify: bool,
+        tokenizer: Option<Py<PyAny>>,
+        scorer: impl Scorer + 'static,
+    ) -> PyResult<Retriever> {
+        let mut reader = if mmap { Reader::map(&path, verify)? } else { Reader::open(&path, verify)? };
+        Retriever::read_index(&mut reader, verify, tokenizer, Some(Box::new(scorer)))
+    }
+
+    /// Splits documents, in any of the shapes `index` accepts, into their values and their names if any.
+    fn split_documents<'py>(documents: &Bound<'py, PyAny>) -> PyResult<(Bound<'py, PyList>, Option<Vec<String>>)> {
+        let py = documents.py();
+        let invalid = || {
+            PyValueError::new_err("documents must be a list of documents, a list of (id, document) pairs or a dict mapping ids to documents, ids being strings")
+        };
+
+        if let Ok(dict) = documents.downcast::<PyDict>() {
+            let names = dict.keys().iter().map(|key| key.extract().map_err(|_| invalid())).collect::<PyResult<_>>()?;
+            return Ok((dict.values(), Some(names)));
+        }
 
-        let doc_lengths: Vec<f32> = corpus.iter().map(|doc| doc.len() as f32).collect();
-        let avg_doc_len = doc_lengths.iter().sum::<f32>() / (doc_lengths.len() as f32);
+        let list = documents.downcast::<PyList>().map_err(|_| invalid())?;
+        let is_pair = |item: &Bound<'py, PyAny>| item.is_instance_of::<PyTuple>();
+        if !list.iter().next().is_some_and(|item| is_pair(&item)) {
+            if list.iter().any(|item| is_pair(&item)) {
+                return Err(invalid());
+            }
+            return Ok((list.clone(), None));
+        }
 
-        self.n_docs = doc_lengths.len();
-        let n_terms = self.vocab.len();
+        let mut names = Vec::with_capacity(list.len());
+        let mut texts = Vec::with_capacity(list.len());
+        for item in list.iter() {
+            let (name, text): (String, Bound<'py, PyAny>) = item.extract().map_err(|_| invalid())?;
+            names.push(name);
+            texts.push(text);
+        }
+        Ok((PyList::new(py, texts)?, Some(names)))
+    }
 
-        let idf_array = Retriever::compute_idf_array(n_terms, self.n_docs, &doc_frequencies);
+    fn clear(&mut self) {
+        self.vocab = Default::default();
+        self.dictionary = TermDictionary::default();
+        self.n_docs = 0;
+        self.total_doc_length = 0.0;
+        self.segments.clear();
+        self.documents = Documents::default();
+        self.statistics = StatisticsCache::default();
+    }
 
-        let MatrixParams { rows, cols, scores } = self.prepare_sparse_matrix(&idf_array, &doc_frequencies, &term_frequencies, &doc_lengths, &avg_doc_len);
+    /// With `replace`, the documents replace the indexed ones, which are only dropped once the new
+    /// ones are checked and tokenized, so that invalid documents leave the index as it was.
+    fn add_documents<'py>(&mut self, documents: &Bound<'py, PyAny>, pretokenized: bool, replace: bool) -> PyResult<()> {
+        let (values, names) = Retriever::split_documents(documents)?;
 
-        let score_matrix = TriMatI::<f32, u32>::from_triplets(
-            (self.n_docs, n_terms),
-            rows.to_vec(),
-            cols.to_vec(),
-            scores.to_vec()
-        ).to_csc();
+        let conflict = names.as_deref().and_then(|names| {
+            if replace { Documents::default().find_conflict(names) } else { self.documents.find_conflict(names) }
+        });
+        if let Some(name) = conflict {
+            return Err(PyValueError::new_err(format!("duplicate document id {name:?}")));
+        }
 
-        self.matrix_components = MatrixComponents {
-            indices: score_matrix.indices().to_vec(),
-            values: score_matrix.data().to_vec(),
-            indptr: score_matrix.indptr().into_raw_storage().to_vec(),
+        let corpora = if replace {
+            let mut vocab = Vocab::default();
+            let corpora = self.tokenize(&values, pretokenized, &mut vocab)?;
+            self.clear();
+            self.vocab = vocab;
+            corpora
+        } else {
+            self.tokenize_new(&values, pretokenized)?
         };
+        let n_added = self.internal_add(corpora);
+        match names {
+            Some(names) => self.documents.push_named(names),
+            None => self.documents.push(n_added),
+        }
+        Ok(())
     }
 
-    fn compute_frequencies(corpus: &Corpus) -> Frequencies {
-        let mut doc_frequencies: DocFrequencies = HashMap::new();
-        let mut term_frequencies: TermFrequencies = Vec::with_capacity(corpus.len());
+    fn update_document(&mut self, doc_id: DocId, document: &Bound<'_, PyAny>, pretokenized: bool) -> PyResult<()> {
+        let position = self.documents.position(&doc_id).ok_or_else(|| PyKeyError::new_err(doc_id))?;
 
-        for terms in corpus {
-            let mut term_count: HashMap<u32, f32> = HashMap::new();
-            for &term in terms {
-                term_count.entry(term).and_modify(|count| *count += 1.0).or_insert(1.0);
+        let corpora = self.tokenize_new(&PyList::new(document.py(), [document])?, pretokenized)?;
+        self.internal_add(corpora);
+        self.documents.delete(position);
+        self.documents.push_replacement(position);
+        Ok(())
+    }
+
+    /// Tokenizes documents added to the index, extending its vocabulary.
+    fn tokenize_new(&mut self, documents: &Bound<'_, PyList>, pretokenized: bool) -> PyResult<Vec<Corpus>> {
+        let mut vocab = std::mem::take(&mut self.vocab);
+        let corpora = self.tokenize(documents, pretokenized, &mut vocab);
+        self.vocab = vocab;
+        corpora
+    }
+
+    /// Maps documents to term ids, as a single corpus or one corpus per field, extending `vocab`.
+    fn tokenize(&self, documents: &Bound<'_, PyList>, pretokenized: bool, vocab: &mut Vocab) -> PyResult<Vec<Corpus>> {
+        let tokenize_values = |values: &Bound<'_, PyList>, vocab: &mut Vocab| {
+            if pretokenized {
+                let documents = values
+                    .iter()
+                    .map(|tokens| Ok(tokens.extract::<Vec<Token>>()?.into_iter().map(String::from).collect()))
+                    .collect::<PyResult<Vec<Vec<String>>>>()?;
+                Ok(Tokenizer::encode(&documents, vocab))
+            } else {
+                self.tokenizer.perform(values, vocab)
             }
+        };
+
+        if self.fields.is_empty() {
+            return Ok(vec![tokenize_values(documents, vocab)?]);
+        }
+
+        let py = documents.py();
+        let empty = if pretokenized { PyList::empty(py).into_any() } else { PyString::new(py, "").into_any() };
+        Field::split(&self.fields, documents, empty)?
+            .iter()
+            .map(|values| tokenize_values(values, vocab))
+            .collect()
+    }
+
+    fn internal_add(&mut self, corpora: Vec<Corpus>) -> usize {
+        let n_added = corpora.first().map_or(0, |corpus| corpus.documents.len());
+        if n_added == 0 {
+            return 0;
+        }
+
+        let segment = if self.fields.is_empty() {
+            Segment::build(&corpora[0], self.vocab.len(), self.positions)
+        } else {
+            Segment::build_fields(&corpora, self.vocab.len(), self.positions)
+        };
+        self.n_docs += segment.n_docs();
+        self.total_doc_length += segment.doc_lengths.iter().map(|&len| len as f64).sum::<f64>();
+        self.segments.push(segment);
+        self.dictionary.extend(&self.vocab);
+        self.statistics = StatisticsCache::default();
+        n_added
+    }
+
+    fn doc_frequency(&self, term: usize) -> u32 {
+        self.segments.iter().map(|segment| segment.postings.doc_frequency(term)).sum()
+    }
+
+    /// Id of the term of `token`, `None` if no document contains it. The vocabulary keeps the tokens
+    /// of the documents of a failed `add`, and their infinite IDF would turn scores into NaN.
+    fn indexed_term(&self, token: &str) -> Option<usize> {
+        self.vocab.get(token).map(|&term| term as usize).filter(|&term| self.doc_frequency(term) > 0)
+    }
+
+    fn idf(&self, term: usize) -> f32 {
+        let idf = self.scorer.idf(self.doc_frequency(term) as f32, self.n_docs as f32);
+        if self.scorer.floors_idf() {
+            return self.scorer.floor_idf(idf, self.average_idf());
+        }
+        idf
+    }
+
+    /// Mean IDF over the terms still present in the index, computed once per index state.
+    fn average_idf(&self) -> f32 {
+        *self.statistics.average_idf.get_or_init(|| {
+            let idfs: Vec<f32> = (0..self.vocab.len())
+                .map(|term| self.doc_frequency(term))
+                .filter(|&df| df > 0)
+                .map(|df| self.scorer.idf(df as f32, self.n_docs as f32))
+                .collect();
+            idfs.iter().sum::<f32>() / idfs.len().max(1) as f32
+        })
+    }
 
-            for unique_term in term_count.keys().cloned(){
-                doc_frequencies.entry(unique_term).and_modify(|count| *count += 1).or_insert(1);
+    /// One over the euclidean norm of each document's term weights, zero for empty documents.
+    fn inverse_doc_norms(&self) -> &[f32] {
+        self.statistics.inverse_doc_norms.get_or_init(|| {
+            let avg_doc_len = (self.total_doc_length / self.n_docs as f64) as f32;
+            let mut squared_norms = vec![0.0; self.n_docs];
+
+            for term in 0..self.vocab.len() {
+                let idf = self.idf(term);
+                let mut doc_offset = 0;
+
+                for segment in &self.segments {
+                    let (doc_indices, term_frequencies) = segment.postings.postings(term);
+                    for (&doc, &tf) in doc_indices.iter().zip(term_frequencies) {
+                        let doc = doc as usize;
+                        let weight = idf * self.scorer.tf_weight(tf, segment.doc_lengths[doc], avg_doc_len);
+                        squared_norms[doc_offset + doc] += weight * weight;
+                    }
+                    doc_offset += segment.n_docs();
+                }
             }
 
-            let term_count_len = term_count.len();
+            squared_norms
+                .into_iter()
+                .map(|squared_norm| if squared_norm > 0.0 { 1.0 / squared_norm.sqrt() } else { 0.0 })
+                .collect()
+        })
+    }
 
-            let keys = Array1::from_shape_fn(term_count_len, |i| {
-                *term_count.keys().nth(i).unwrap()
-            });
+    /// Average length of each field, computed once per index state.
+    fn average_field_lengths(&self) -> &[f32] {
+        self.statistics.average_field_lengths.get_or_init(|| {
+            (0..self.fields.len())
+                .map(|field| {
+                    let total: f64 = self.segments
+                        .iter()
+                        .flat_map(|segment| segment.fields[field].lengths.iter())
+                        .map(|&len| len as f64)
+                        .sum();
+                    (total / self.n_docs as f64) as f32
+                })
+                .collect()
+        })
+    }
 
-            let values = Array1::from_shape_fn(term_count_len, |i| {
-                *term_count.values().nth(i).unwrap()
-            });
+    fn write_index(&self, writer: &mut Writer) -> io::Result<()> {
+        match self.scorer.as_scoring() {
+            Some(scoring) => {
+                writer.write_u32(0)?;
+                scoring.write(writer)?;
+            }
+            None => writer.write_u32(1)?,
+        }
+        self.tokenizer.config().write(writer)?;
+        self.query_tf.write(writer)?;
+        writer.write_u32(self.positions as u32)?;
+
+        writer.write_u64(self.fields.len() as u64)?;
+        for field in &self.fields {
+            field.write(writer)?;
+        }
 
-            term_frequencies.push((keys, values));
+        let mut terms = vec![""; self.vocab.len()];
+        for (term, &id) in &self.vocab {
+            terms[id as usize] = term;
+        }
+        writer.write_u64(terms.len() as u64)?;
+        for term in terms {
+            writer.write_str(term)?;
         }
 
-        Frequencies { doc_frequencies, term_frequencies }
+        writer.write_u64(self.segments.len() as u64)?;
+        for segment in &self.segments {
+            segment.write(writer)?;
+        }
+        self.documents.write(writer)
     }
 
-    fn compute_idf_array(n_terms: usize, n_docs: usize, doc_frequencies: &DocFrequencies) -> IdfArray {
-        let mut idf = Array1::<f32>::zeros(n_terms);
+    fn read_index(
+        reader: &mut Reader,
+        verify: bool,
+        splitter: Option<Py<PyAny>>,
+        scorer: Option<Box<dyn Scorer>>,
+    ) -> PyResult<Retriever> {
+        let custom_scorer = reader.read_u32()? != 0;
+        let scorer: Box<dyn Scorer> = match (custom_scorer, scorer) {
+            (false, None) => Box::new(Scoring::read(reader)?),
+            (true, Some(scorer)) => scorer,
+            (true, None) => return Err(PyValueError::new_err("this index was built with a custom scorer, pass it to load_with_scorer")),
+            (false, Some(_)) => return Err(PyValueError::new_err("this index was built without a custom scorer")),
+        };
 
-        let n_log = (n_docs as f32).ln();
-        for (term, freq) in doc_frequencies {
-            idf[*term as usize] = n_log - (*freq as f32).ln();
+        let config = TokenizerConfig::read(reader)?;
+        if config.custom_splitter != splitter.is_some() {
+            return Err(PyValueError::new_err(if config.custom_splitter {
+                "this index was built with a custom tokenizer, pass it to load"
+            } else {
+                "this index was built without a custom tokenizer"
+            }));
         }
+        let tokenizer = Tokenizer::from_config(config, splitter)
+            .map_err(|e| invalid_data(&e.to_string()))?;
+        let query_tf = QueryTf::read(reader)?;
+        let positions = reader.read_u32()? != 0;
 
-        idf
+        let n_fields = reader.read_u64()?;
+        let fields = (0..n_fields).map(|_| Field::read(reader)).collect::<io::Result<Vec<_>>>()?;
+
+        let n_terms = reader.read_u64()?;
+        let vocab = (0..n_terms)
+            .map(|id| Ok((reader.read_str()?, id as u32)))
+            .collect::<io::Result<Vocab>>()?;
+
+        let n_segments = reader.read_u64()?;
+        let segments = (0..n_segments)
+            .map(|_| Segment::read(reader, verify))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        if segments.iter().any(|segment| segment.fields.len() != fields.len()) {
+            return Err(invalid_data("inconsistent segment fields").into());
+        }
+        if segments.iter().any(|segment| segment.positions.is_some() != positions) {
+            return Err(invalid_data("inconsistent segment positions").into());
+        }
+        let references_unknown_terms = segments
+            .iter()
+            .flat_map(|segment| std::iter::once(&segment.postings).chain(segment.fields.iter().map(|field| &field.postings)))
+            .any(|postings| postings.indptr.len() > vocab.len() + 1);
+        if references_unknown_terms {
+            return Err(invalid_data("index matrix references unknown terms").into());
+        }
+
+        let n_docs = segments.iter().map(Segment::n_docs).sum();
+        let documents = Documents::read(reader, n_docs)?;
+
+        Ok(Self {
+            scorer,
+            tokenizer,
+            fields,
+            query_tf,
+            positions,
+            dictionary: TermDictionary::build(&vocab),
+            vocab,
+            n_docs,
+            total_doc_length: segments.iter().flat_map(|segment| segment.doc_lengths.iter()).map(|&len| len as f64).sum(),
+            segments,
+            documents,
+            statistics: StatisticsCache::default(),
+            score_buffer: ThreadLocal::default(),
+        })
+    }
+
+    /// The weight of each field is empty for retrievers without fields.
+    fn search_options(
+        &self,
+        selection: Option<FieldSelection>,
+        max_expansions: usize,
+        fuzziness: u32,
+        prefix_length: usize,
+    ) -> PyResult<SearchOptions> {
+        if fuzziness > 2 {
+            return Err(PyValueError::new_err("fuzziness must be 0, 1 or 2"));
+        }
+        let field_weights = match selection {
+            Some(_) if self.fields.is_empty() => return Err(PyValueError::new_err("this retriever was built without fields")),
+            Some(selection) => selection.weights(&self.fields).map_err(PyValueError::new_err)?,
+            None => self.fields.iter().map(|field| field.weight).collect(),
+        };
+        Ok(SearchOptions { field_weights, max_expansions, fuzziness, prefix_length })
+    }
+
+    /// Ids of the indexed terms matching `pattern`, each with its similarity to the pattern, keeping
+    /// the `max_expansions` most similar, then in the most documents.
+    fn expand(&self, pattern: &Pattern, options: &SearchOptions) -> Vec<(usize, f32)> {
+        // Regex tokenization lowercases documents, a custom tokenizer may not.
+        let text = if self.tokenizer.calls_python() { Cow::Borrowed(&pattern.text) } else { Cow::Owned(pattern.text.to_lowercase()) };
+        let matches = match pattern.kind {
+            PatternKind::Wildcard => self.dictionary.matching(&text).into_iter().map(|term| (term, 1.0)).collect(),
+            PatternKind::Fuzzy { max_distance } => self.dictionary.fuzzy(&text, max_distance, options.prefix_length),
+        };
+
+        let mut terms: Vec<(usize, f32, u32)> = matches
+            .into_iter()
+            .map(|(term, similarity)| (term as usize, similarity, self.doc_frequency(term as usize)))
+            .filter(|&(_, _, df)| df > 0)
+            .collect();
+        terms.sort_unstable_by(|a, b| b.1.total_cmp(&a.1).then(b.2.cmp(&a.2)).then(a.0.cmp(&b.0)));
+        terms.truncate(options.max_expansions);
+        terms.into_iter().map(|(term, similarity, _)| (term, similarity)).collect()
     }
 
-    fn prepare_sparse_matrix(
+    /// BM25F: sums the weighted, length-normalized frequencies of `term` in each field before
+    /// saturating them. Lengths being normalized already, the scorer sees documents of average length.
+    #[allow(clippy::too_many_arguments)]
+    fn accumulate_fields(
         &self,
-        idf_array: &IdfArray,
-        doc_frequencies: &DocFrequencies,
-        term_frequencies: &TermFrequencies,
-        doc_lengths: &Vec<f32>,
-        avg_doc_len: &f32) -> MatrixParams {
+        term: usize,
+        idf: f32,
+        segment: &Segment,
+        field_weights: &[f32],
+        avg_doc_len: f32,
+        field_tfs: &mut [f32],
+        scores: &mut [f32],
+    ) {
+        let fields = self.fields.iter().zip(&segment.fields).zip(field_weights).zip(self.average_field_lengths());
+        for (((field, postings), &weight), &avg_field_len) in fields {
+            if weight == 0.0 {
+                continue;
+            }
 
-        let size = doc_frequencies.values().sum::<u32>() as usize;
+            let (doc_indices, term_frequencies) = postings.postings.postings(term);
+            for (&doc, &tf) in doc_indices.iter().zip(term_frequencies) {
+                let doc = doc as usize;
+                field_tfs[doc] += weight * field.normalized_tf(tf, postings.lengths[doc], avg_field_len);
+            }
+        }
 
-        let mut rows = Array1::<u32>::zeros(size);
-        let mut cols = Array1::<u32>::zeros(size);
-        let mut scores = Array1::<f32>::zeros(size);
+        // Documents only containing the term in fields that are not searched are left unscored.
+        let absent_tf_weight = self.scorer.absent_tf_weight();
+        for &doc in segment.postings.postings(term).0 {
+            let tf = std::mem::take(&mut field_tfs[doc as usize]);
+            if tf > 0.0 {
+                scores[doc as usize] += idf * (self.scorer.tf_weight(tf, avg_doc_len, avg_doc_len) - absent_tf_weight);
+            }
+        }
+    }
 
-        let mut step = 0;
+    /// Internal ids of the documents containing `term`, across segments.
+    fn term_documents(&self, term: usize) -> impl Iterator<Item = usize> + '_ {
+        let mut doc_offset = 0;
+        self.segments.iter().flat_map(move |segment| {
+            let segment_offset = doc_offset;
+            doc_offset += segment.n_docs();
+            segment.postings.postings(term).0.iter().map(move |&doc| segment_offset + doc as usize)
+        })
+    }
 
-        for (i, (terms, tf_array)) in term_frequencies.iter().enumerate() {
-            let doc_length = doc_lengths[i];
+    /// Counts the required clauses each document matches, returning how many a document must match,
+    /// or `None` if a required clause matches no indexed term. A clause is matched by any of its terms.
+    /// Clauses are counted in order, a document only matching one if it matched all the previous ones,
+    /// so that a document containing several terms of a clause is not counted twice. With `fuzzy`,
+    /// required tokens missing from the index are among the patterns of `expansions`.
+    fn match_required(
+        &self,
+        query: &Query,
+        expansions: &[(&Pattern, Vec<(usize, f32)>)],
+        fuzzy: bool,
+        required_matches: &mut RequiredMatches,
+    ) -> Option<u32> {
+        let mut clauses: Vec<Vec<usize>> = Vec::new();
+        for token in &query.required {
+            match self.indexed_term(token) {
+                Some(term) => clauses.push(vec![term]),
+                None if fuzzy => {}
+                None => return None,
+            }
+        }
+        for (pattern, terms) in expansions {
+            if pattern.occur == Occur::Must {
+                if terms.is_empty() {
+                    return None;
+                }
+                clauses.push(terms.iter().map(|&(term, _)| term).collect());
+            }
+        }
 
-            let tfc = tf_array * (self.k1 + 1.0) /
-                (tf_array + self.k1 * (1.0 - self.b + self.b * doc_length / *avg_doc_len));
+        let required_phrases: Vec<&Phrase> = query.phrases.iter().filter(|phrase| phrase.occur == Occur::Must).collect();
+        if required_phrases.iter().any(|phrase| self.phrase_terms(phrase).is_none()) {
+            return None;
+        }
 
-            let score_slice = idf_array.select(Axis(0), &terms.map(|t| *t as usize).as_slice().unwrap()) * &tfc;
+        required_matches.start(self.n_docs);
+        for (clause, terms) in clauses.iter().enumerate() {
+            for &term in terms {
+                for doc in self.term_documents(term) {
+                    required_matches.count(doc, clause as u32);
+                }
+            }
+        }
 
-            let start = step;
-            let end = start + score_slice.len();
+        let prohibited_patterns = expansions
+            .iter()
+            .filter(|(pattern, _)| pattern.occur == Occur::MustNot)
+            .flat_map(|(_, terms)| terms.iter().map(|&(term, _)| term));
+        let prohibited_terms = query.prohibited.iter().filter_map(|token| self.vocab.get(token)).map(|&term| term as usize);
+        for term in prohibited_terms.chain(prohibited_patterns) {
+            for doc in self.term_documents(term) {
+                required_matches.prohibit(doc);
+            }
+        }
 
-            rows.slice_mut(s![start..end]).fill(i as u32);
-            cols.slice_mut(s![start..end]).assign(&terms);
-            scores.slice_mut(s![start..end]).assign(&score_slice);
+        for phrase in query.phrases.iter().filter(|phrase| phrase.occur == Occur::MustNot) {
+            if let Some(terms) = self.phrase_terms(phrase) {
+                self.match_phrase(phrase, &terms, |doc, _, _| required_matches.prohibit(doc));
+            }
+        }
+        // Required phrases are the last clauses, counted while scoring them as it finds their occurrences anyway.
+        Some((clauses.len() + required_phrases.len()) as u32)
+    }
 
-            step = end;
+    fn check_phrases(&self, query: &Query) -> PyResult<()> {
+        if !self.positions && !query.phrases.is_empty() {
+            return Err(PyValueError::new_err("phrase queries need a retriever built with positions=True"));
         }
+        Ok(())
+    }
+
+    /// Term ids of a phrase, `None` if one of its tokens is not indexed.
+    fn phrase_terms(&self, phrase: &Phrase) -> Option<Vec<usize>> {
+        phrase.tokens.iter().map(|token| self.vocab.get(token).map(|&term| term as usize)).collect()
+    }
+
+    /// Calls `on_match` with each document containing `phrase`, of term ids `terms`, within its slop,
+    /// how many times it does, and the document length.
+    fn match_phrase(&self, phrase: &Phrase, terms: &[usize], mut on_match: impl FnMut(usize, f32, f32)) {
+        let mut term_positions = vec![Vec::new(); terms.len()];
+        let mut doc_offset = 0;
+
+        for segment in &self.segments {
+            let Some(positions) = &segment.positions else { continue };
+            let indices = &segment.postings.indices;
+            let ranges: Vec<Range<usize>> = terms.iter().map(|&term| segment.postings.posting_range(term)).collect();
+            let rarest = ranges.iter().min_by_key(|range| range.len()).unwrap().clone();
+            let mut cursors: Vec<usize> = ranges.iter().map(|range| range.start).collect();
 
-        MatrixParams { rows, cols, scores}
+            'docs: for &doc in &indices[rarest] {
+                for (cursor, range) in cursors.iter_mut().zip(&ranges) {
+                    while *cursor < range.end && indices[*cursor] < doc {
+                        *cursor += 1;
+                    }
+                    if *cursor == range.end {
+                        break 'docs;
+                    }
+                    if indices[*cursor] != doc {
+                        continue 'docs;
+                    }
+                }
+
+                for (positions_of_term, &cursor) in term_positions.iter_mut().zip(&cursors) {
+                    positions.decode(cursor, positions_of_term);
+                }
+                let frequency = phrase_frequency(&term_positions, &phrase.positions, phrase.slop);
+                if frequency > 0 {
+                    on_match(doc_offset + doc as usize, frequency as f32, segment.doc_lengths[doc as usize]);
+                }
+            }
+            doc_offset += segment.n_docs();
+        }
     }
 
-    fn internal_top_n(&self, query: &String, n: usize) -> SearchResult {
-        let tokenized_query = self.tokenizer.perform_simple(query);
+    /// Top `n` documents for a query of plain terms with dynamic pruning, see `pruning::top_n`.
+    /// Returns `None` where it could differ from exhaustive search: for scorers without bounds,
+    /// negative term weights, and when absent terms weigh something but fewer than `n` documents
+    /// score above zero, exhaustive search then also returning documents not matching the query.
+    fn pruned_top_n(&self, query_terms: &[(usize, f32)], n: usize, avg_doc_len: f32) -> Option<SearchResult> {
+        if n == 0 {
+            return None;
+        }
+
+        let terms: Vec<(usize, f32)> = query_terms.iter().map(|&(i, query_weight)| (i, query_weight * self.idf(i))).collect();
+        if terms.iter().any(|&(_, idf)| idf < 0.0 || idf.is_nan()) {
+            return None;
+        }
+
+        let has_deletions = self.documents.n_deleted() > 0;
+        let is_deleted = |doc: usize| has_deletions && self.documents.is_deleted(doc);
+        let absent_tf_weight = self.scorer.absent_tf_weight();
+        let absent_score: f32 = terms.iter().fold(0.0, |absent_score, &(_, idf)| absent_score + idf * absent_tf_weight);
+
+        // Matched documents are left zeroed by the search.
+        let scores = &mut self.score_buffer.get_or_default().borrow_mut().scores;
+        scores.resize(self.n_docs, 0.0);
+        let top = pruning::top_n(self.scorer.as_ref(), &self.segments, &terms, avg_doc_len, is_deleted, n, scores)?;
+        if absent_score != 0.0 && (top.len() < n || top.threshold() <= 0.0) {
+            return None;
+        }
 
-        let scores: &mut Vec<f32> = &mut *self.score_buffer.get_or(|| RefCell::new(vec![0.0; self.n_docs])).borrow_mut();
-        scores.fill(0.0);
+        Some(top
+            .into_sorted()
+            .into_iter()
+            .map(|(idx, score)| (self.documents.doc_id(idx), score + absent_score))
+            .collect())
+    }
+
+    fn internal_top_n(&self, query: &Query, n: usize, options: &SearchOptions) -> SearchResult {
+        // With `fuzziness`, query terms missing from the index stand for the indexed terms close to them.
+        let fuzzy_patterns: Vec<Pattern> = if options.fuzziness == 0 {
+            Vec::new()
+        } else {
+            query.terms
+                .iter()
+                .filter(|(token, _)| self.indexed_term(token).is_none())
+                .map(|(token, weight)| Pattern {
+                    text: token.clone(),
+                    kind: PatternKind::Fuzzy { max_distance: options.fuzziness },
+                    weight: *weight,
+                    occur: if query.required.contains(token) { Occur::Must } else { Occur::Should },
+                })
+                .collect()
+        };
+        let expansions: Vec<(&Pattern, Vec<(usize, f32)>)> = query.patterns
+            .iter()
+            .chain(&fuzzy_patterns)
+            .map(|pattern| (pattern, self.expand(pattern, options)))
+            .collect();
 
-        let mut query_indices: Vec<usize> = tokenized_query
+        let mut terms: Vec<(usize, f32)> = query.terms
             .iter()
-            .filter_map(|term| self.vocab.get(term).cloned())
-            .map(|idx| idx as usize)
+            .filter_map(|(token, weight)| self.indexed_term(token).map(|term| (term, *weight)))
             .collect();
-        query_indices.sort_unstable(); // Sort to improve cache access pattern
+        for (pattern, expanded) in expansions.iter().filter(|(pattern, _)| pattern.occur != Occur::MustNot) {
+            terms.extend(expanded.iter().map(|&(term, similarity)| (term, pattern.weight * similarity)));
+        }
 
-        if query_indices.is_empty() {
+        let query_terms = self.query_tf.term_weights(terms);
+        let scored_phrases: Vec<&Phrase> = query.phrases.iter().filter(|phrase| phrase.occur != Occur::MustNot).collect();
+        if query_terms.is_empty() && scored_phrases.is_empty() {
             return vec![];
         }
 
-        let raw_indptr = &self.matrix_components.indptr;
-        const CHUNK_SIZE: usize = 4;
-        for &i in query_indices.iter() {
-            let start = raw_indptr[i] as usize;
-            let end = raw_indptr[i + 1] as usize;
+        let is_boolean = !query.required.is_empty()
+            || !query.prohibited.is_empty()
+            || query.phrases.iter().any(|phrase| phrase.occur != Occur::Should)
+            || expansions.iter().any(|(pattern, _)| pattern.occur != Occur::Should);
+        let avg_doc_len = (self.total_doc_length / self.n_docs as f64) as f32;
+        let has_deletions = self.documents.n_deleted() > 0;
+
+        if !is_boolean && scored_phrases.is_empty() && self.fields.is_empty() {
+            if let Some(result) = self.pruned_top_n(&query_terms, n, avg_doc_len) {
+                return result;
+            }
+        }
+
+        let buffer = &mut *self.score_buffer.get_or_default().borrow_mut();
+        let ScoreBuffer { scores, field_tfs, required_matches, matches } = buffer;
+
+        let n_required = if is_boolean {
+            match self.match_required(query, &expansions, options.fuzziness > 0, required_matches) {
+                Some(n_required) => n_required,
+                None => return vec![],
+            }
+        } else {
+            0
+        };
+
+        scores.resize(self.n_docs, 0.0);
+        let n_postings = query_terms.iter().map(|&(i, _)| self.doc_frequency(i) as usize).sum();
+        matches.start(self.n_docs, n_postings);
+        if !self.fields.is_empty() {
+            field_tfs.resize(self.n_docs, 0.0);
+        }
+
+        let absent_tf_weight = self.scorer.absent_tf_weight();
+        let mut absent_score = 0.0;
 
-            let doc_indices = &self.matrix_components.indices[start..end];
-            let term_scores = &self.matrix_components.values[start..end];
+        // Postings of deleted documents are scored too, selection skips them.
+        for &(i, query_weight) in query_terms.iter() {
+            let idf = query_weight * self.idf(i);
+            absent_score += idf * absent_tf_weight;
+            let mut doc_offset = 0;
 
-            let chunks = doc_indices.len() / CHUNK_SIZE;
-            let remainder = doc_indices.len() % CHUNK_SIZE;
+            for segment in &self.segments {
+                let segment_docs = doc_offset..doc_offset + segment.n_docs();
+                if self.fields.is_empty() {
+                    let (doc_indices, term_frequencies) = segment.postings.postings(i);
+                    self.scorer.accumulate(idf, doc_indices, term_frequencies, &segment.doc_lengths, avg_doc_len, &mut scores[segment_docs]);
+                    matches.extend(doc_offset, doc_indices);
+                } else {
+                    let field_tfs = &mut field_tfs[segment_docs.clone()];
+                    self.accumulate_fields(i, idf, segment, &options.field_weights, avg_doc_len, field_tfs, &mut scores[segment_docs]);
+                    let searched_fields = segment.fields.iter().zip(&options.field_weights).filter(|(_, &weight)| weight != 0.0);
+                    for (field, _) in searched_fields {
+                        matches.extend(doc_offset, field.postings.postings(i).0);
+                    }
+                }
+                doc_offset += segment.n_docs();
+            }
+        }
 
-            for c in 0..chunks {
-                let base = c * CHUNK_SIZE;
+        // Phrases weigh the sum of the IDFs of their terms, and are saturated like a term on their frequency.
+        let n_required_phrases = scored_phrases.iter().filter(|phrase| phrase.occur == Occur::Must).count() as u32;
+        let mut phrase_clause = n_required.saturating_sub(n_required_phrases);
+        for phrase in scored_phrases {
+            let Some(terms) = self.phrase_terms(phrase) else { continue };
+            let idf = phrase.weight * terms.iter().map(|&term| self.idf(term)).sum::<f32>();
+            let is_required = phrase.occur == Occur::Must;
 
-                unsafe {
-                    scores[*doc_indices.get_unchecked(base) as usize] += *term_scores.get_unchecked(base);
-                    scores[*doc_indices.get_unchecked(base + 1) as usize] += *term_scores.get_unchecked(base + 1);
-                    scores[*doc_indices.get_unchecked(base + 2) as usize] += *term_scores.get_unchecked(base + 2);
-                    scores[*doc_indices.get_unchecked(base + 3) as usize] += *term_scores.get_unchecked(base + 3);
+            self.match_phrase(phrase, &terms, |doc, frequency, doc_len| {
+                scores[doc] += idf * self.scorer.tf_weight(frequency, doc_len, avg_doc_len);
+                matches.insert(doc);
+                if is_required {
+                    required_matches.count(doc, phrase_clause);
                 }
+            });
+            if is_required {
+                phrase_clause += 1;
             }
+        }
+
+        if self.scorer.normalizes_documents() {
+            let inverse_doc_norms = self.inverse_doc_norms();
+            matches.for_each(|doc| scores[doc] *= inverse_doc_norms[doc]);
+        }
+
+        let is_eligible = |doc: usize| {
+            !(has_deletions && self.documents.is_deleted(doc)) && (!is_boolean || required_matches.get(doc) == n_required)
+        };
+        let mut top = TopK::new(n, self.n_docs);
+        let mut threshold = top.threshold();
+        matches.for_each(|doc| {
+            let score = scores[doc];
+            if score >= threshold && is_eligible(doc) {
+                top.push(doc, score);
+                threshold = top.threshold();
+            }
+        });
 
-            let start_remainder = chunks * CHUNK_SIZE;
-            for i in 0..remainder {
-                scores[doc_indices[start_remainder + i] as usize] += term_scores[start_remainder + i];
+        // Documents without any query term still score `absent_score` when absent terms weigh
+        // something. They all tie, so once one of them does not enter, no later one does.
+        if absent_score != 0.0 && n_required == 0 {
+            let unmatched = (0..self.n_docs).filter(|&doc| !matches.contains(doc) && is_eligible(doc));
+            for doc in unmatched {
+                if !top.push(doc, 0.0) {
+                    break;
+                }
             }
         }
+        matches.clear(scores);
+        required_matches.clear();
+
+        top
+            .into_sorted()
+            .into_iter()
+            .map(|(idx, score)| (self.documents.doc_id(idx), score + absent_score))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::WeightedTokens;
+
+    fn retriever(method: &str, exact: bool) -> Retriever {
+        Retriever::new(1.2, 0.75, None, None, None, None, method, 0.5, None, 0.25, exact, None, "count", 8.0, false).unwrap()
+    }
+
+    /// Indexes whitespace separated tokens, without going through Python.
+    fn add(retriever: &mut Retriever, texts: &[&str]) {
+        let documents: Vec<Vec<String>> = texts.iter().map(|text| text.split_whitespace().map(String::from).collect()).collect();
+        let corpus = Tokenizer::encode(&documents, &mut retriever.vocab);
+        let n_added = retriever.internal_add(vec![corpus]);
+        retriever.documents.push(n_added);
+    }
+
+    fn search(retriever: &Retriever, query: &str, n: usize) -> Vec<(usize, f32)> {
+        let options = retriever.search_options(None, MAX_EXPANSIONS, 0, 0).unwrap();
+        let terms: WeightedTokens = query.split_whitespace().map(|token| (token.to_string(), 1.0)).collect();
+        retriever
+            .internal_top_n(&Query::from(terms), n, &options)
+            .into_iter()
+            .map(|(doc_id, score)| match doc_id {
+                DocId::Position(position) => (position, score),
+                DocId::Name(name) => panic!("unexpected document name {name:?}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn terms_of_purged_documents_are_not_scored() {
+        for (method, exact) in [("atire", false), ("bm25+", false), ("bm25+", true), ("bm25l", true), ("tfidf", false)] {
+            let mut retriever = retriever(method, exact);
+            add(&mut retriever, &["aa bb", "cc dd", "cc ee"]);
+            retriever.delete(vec![DocId::Position(0)]).unwrap();
+            retriever.compact();
+
+            let results = search(&retriever, "aa dd", 10);
+            assert_eq!(results[0].0, 1, "{method}");
+            assert!(results.iter().all(|&(_, score)| score.is_finite()), "{method}: {results:?}");
+            assert_eq!(results.len(), if exact { 2 } else { 1 }, "{method}");
+        }
+    }
+
+    #[test]
+    fn compaction_drops_the_terms_of_purged_documents() {
+        let mut compacted = retriever("robertson", false);
+        add(&mut compacted, &["aa bb", "cc dd aa"]);
+        add(&mut compacted, &["cc ee", "gg", "bb ff cc"]);
+        compacted.delete(vec![DocId::Position(1), DocId::Position(3)]).unwrap();
+        compacted.compact();
+
+        let mut fresh = retriever("robertson", false);
+        add(&mut fresh, &["aa bb", "cc ee", "bb ff cc"]);
+
+        assert_eq!(compacted.vocab.len(), 5);
+        assert!((0..compacted.vocab.len()).all(|term| compacted.doc_frequency(term) > 0));
+        for query in ["aa cc", "dd ee", "bb ff gg", "cc"] {
+            let expected: Vec<(usize, f32)> = search(&fresh, query, 10)
+                .into_iter()
+                .map(|(doc, score)| ([0, 2, 4][doc], score))
+                .collect();
+            assert_eq!(search(&compacted, query, 10), expected, "{query}");
+        }
+    }
 
-        let mut indexed_scores = Vec::with_capacity(n);
+    #[test]
+    fn saved_indexes_search_identically() {
+        let path = std::env::temp_dir().join(format!("bm25spyrs-test-{}.idx", std::process::id()));
+        for (method, exact) in [("robertson", false), ("bm25l", true), ("tfidf", false)] {
+            let mut original = retriever(method, exact);
+            add(&mut original, &["heart attack risk", "cat dog", "heart heart failure", "attack dog"]);
+            add(&mut original, &["risk of heart disease", "dog food"]);
+            original.delete(vec![DocId::Position(1)]).unwrap();
+            original.save(path.clone()).unwrap();
 
-        for (idx, &score) in scores.iter().enumerate() {
-            if indexed_scores.len() < n {
-                indexed_scores.push((idx, score));
-                if indexed_scores.len() == n {
-                    indexed_scores.sort_unstable_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+            for mmap in [false, true] {
+                let loaded = Retriever::load(path.clone(), mmap, true, None).unwrap();
+                for query in ["heart attack", "dog", "risk heart failure food", "unknown"] {
+                    let bits = |results: Vec<(usize, f32)>| results.into_iter().map(|(doc, score)| (doc, score.to_bits())).collect::<Vec<_>>();
+                    assert_eq!(bits(search(&loaded, query, 10)), bits(search(&original, query, 10)), "{method} mmap={mmap} {query:?}");
                 }
-            } else if score > indexed_scores.last().unwrap().1 {
-                indexed_scores.pop();
-                let pos = indexed_scores.partition_point(|x| x.1 > score);
-                indexed_scores.insert(pos, (idx, score));
             }
         }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn verify_checks_the_checksum_with_and_without_mmap() {
+        let path = std::env::temp_dir().join(format!("bm25spyrs-test-checksum-{}.idx", std::process::id()));
+        let mut original = retriever("atire", false);
+        add(&mut original, &["heart attack", "cat dog"]);
+        original.save(path.clone()).unwrap();
 
-        indexed_scores
+        // Corrupts the checksum of the header, leaving the index itself intact.
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[12] ^= 0xff;
+        std::fs::write(&path, bytes).unwrap();
+
+        for mmap in [false, true] {
+            assert!(Retriever::load(path.clone(), mmap, true, None).is_err(), "mmap={mmap}");
+            let loaded = Retriever::load(path.clone(), mmap, false, None).unwrap();
+            assert_eq!(search(&loaded, "heart", 10), search(&original, "heart", 10), "mmap={mmap}");
+        }
+        std::fs::remove_file(path).unwrap();
     }
 }
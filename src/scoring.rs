@@ -0,0 +1,254 @@
+use std::io;
+use crate::persistence::{invalid_data, Reader, Writer};
+
+/// BM25 variants, following the definitions used by bm25s (Kamphuis et al., 2020)
+/// so that scores can be compared across libraries, and simpler baselines for ablations.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Method {
+    Robertson,
+    Atire,
+    Lucene,
+    Bm25L,
+    Bm25Plus,
+    /// `(1 + ln tf) * ln(N / df)`, divided by the euclidean norm of the document vector.
+    /// Queries are not normalized, which does not change rankings.
+    TfIdf,
+    /// Raw term frequency, without IDF.
+    Tf,
+}
+
+const METHODS: [(&str, Method); 7] = [
+    ("robertson", Method::Robertson),
+    ("atire", Method::Atire),
+    ("lucene", Method::Lucene),
+    ("bm25l", Method::Bm25L),
+    ("bm25+", Method::Bm25Plus),
+    ("tfidf", Method::TfIdf),
+    ("tf", Method::Tf),
+];
+
+impl Method {
+    pub fn from_name(name: &str) -> Option<Method> {
+        METHODS.iter()
+            .find(|(method, _)| *method == name)
+            .map(|(_, method)| *method)
+    }
+
+    pub fn name(&self) -> &'static str {
+        METHODS.iter().find(|(_, method)| method == self).unwrap().0
+    }
+}
+
+/// IDF formulas that can replace the one of the BM25 method.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Idf {
+    /// The IDF of the selected BM25 method.
+    Method,
+    /// `ln(1 + (N - df + 0.5) / (df + 0.5))`, always positive.
+    Lucene,
+    /// `ln((N - df + 0.5) / (df + 0.5))`, negative values being floored like rank_bm25 does.
+    Robertson,
+    /// `ln((N + 1) / (df + 1)) + 1`, as scikit-learn's smoothed IDF.
+    Smooth,
+}
+
+const IDFS: [(&str, Idf); 4] = [
+    ("method", Idf::Method),
+    ("lucene", Idf::Lucene),
+    ("robertson", Idf::Robertson),
+    ("smooth", Idf::Smooth),
+];
+
+impl Idf {
+    pub fn from_name(name: &str) -> Option<Idf> {
+        IDFS.iter()
+            .find(|(idf, _)| *idf == name)
+            .map(|(_, idf)| *idf)
+    }
+
+    pub fn name(&self) -> &'static str {
+        IDFS.iter().find(|(_, idf)| idf == self).unwrap().0
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Scoring {
+    pub method: Method,
+    pub k1: f32,
+    pub b: f32,
+    /// Lower bound on the contribution of a matching term, only used by BM25L and BM25+.
+    pub delta: f32,
+    pub idf: Idf,
+    /// With the Robertson IDF, negative IDFs are replaced by `epsilon` times the average IDF.
+    pub epsilon: f32,
+    /// Also give BM25L and BM25+ shifts to documents missing a query term, as rank_bm25 does,
+    /// instead of the sparse approximation of bm25s that only shifts matching terms.
+    pub exact: bool,
+}
+
+/// Term weighting plugged into a `Retriever`. A document scores
+/// `idf(df, N) * tf_weight(tf, doc_len, avg_doc_len)` for each query term it contains,
+/// from raw term frequencies and statistics computed over the whole collection.
+pub trait Scorer: Send + Sync {
+    fn idf(&self, df: f32, n_docs: f32) -> f32;
+
+    fn tf_weight(&self, tf: f32, doc_len: f32, avg_doc_len: f32) -> f32;
+
+    fn score(&self, tf: f32, df: f32, doc_len: f32, avg_doc_len: f32, n_docs: f32) -> f32 {
+        self.idf(df, n_docs) * self.tf_weight(tf, doc_len, avg_doc_len)
+    }
+
+    /// Whether negative IDFs are replaced by `floor_idf`, which needs the average IDF of the vocabulary.
+    fn floors_idf(&self) -> bool {
+        false
+    }
+
+    fn floor_idf(&self, idf: f32, _average_idf: f32) -> f32 {
+        idf
+    }
+
+    /// Weight of a query term in documents that do not contain it. It does not depend on the
+    /// document, so it is added to every score as a per-term constant rather than stored.
+    fn absent_tf_weight(&self) -> f32 {
+        0.0
+    }
+
+    /// Upper bound of `tf_weight` over postings of frequency at most `tf` in documents of length
+    /// at least `doc_len`, which lets top-n searches skip documents that cannot rank.
+    /// Scorers returning a bound must have a `tf_weight` non-decreasing in `tf`, non-increasing in
+    /// `doc_len` and never below `absent_tf_weight`; `None` keeps searches exhaustive.
+    fn max_tf_weight(&self, _tf: f32, _doc_len: f32, _avg_doc_len: f32) -> Option<f32> {
+        None
+    }
+
+    /// Whether scores are divided by the euclidean norm of the document's term weights.
+    fn normalizes_documents(&self) -> bool {
+        false
+    }
+
+    /// The built-in configuration this scorer is, which lets indexes using it be saved.
+    fn as_scoring(&self) -> Option<&Scoring> {
+        None
+    }
+
+    /// Adds the weight of a term to the scores of the documents in its postings. Being provided,
+    /// it is compiled for each implementation, so `tf_weight` is not a virtual call per posting.
+    fn accumulate(
+        &self,
+        idf: f32,
+        doc_indices: &[u32],
+        term_frequencies: &[f32],
+        doc_lengths: &[f32],
+        avg_doc_len: f32,
+        scores: &mut [f32],
+    ) {
+        let absent_tf_weight = self.absent_tf_weight();
+        for (&doc, &tf) in doc_indices.iter().zip(term_frequencies) {
+            let doc = doc as usize;
+            scores[doc] += idf * (self.tf_weight(tf, doc_lengths[doc], avg_doc_len) - absent_tf_weight);
+        }
+    }
+}
+
+impl Scorer for Scoring {
+    /// IDF of a term before any flooring, see `floor_idf`.
+    fn idf(&self, df: f32, n_docs: f32) -> f32 {
+        match self.idf {
+            Idf::Method => self.method_idf(df, n_docs),
+            Idf::Lucene => (1.0 + (n_docs - df + 0.5) / (df + 0.5)).ln(),
+            Idf::Robertson => ((n_docs - df + 0.5) / (df + 0.5)).ln(),
+            Idf::Smooth => ((n_docs + 1.0) / (df + 1.0)).ln() + 1.0,
+        }
+    }
+
+    #[inline]
+    fn tf_weight(&self, tf: f32, doc_len: f32, avg_doc_len: f32) -> f32 {
+        let length_norm = 1.0 - self.b + self.b * doc_len / avg_doc_len;
+
+        match self.method {
+            Method::Robertson | Method::Lucene => tf / (self.k1 * length_norm + tf),
+            Method::Atire => tf * (self.k1 + 1.0) / (tf + self.k1 * length_norm),
+            Method::Bm25L => {
+                let c = tf / length_norm;
+                (self.k1 + 1.0) * (c + self.delta) / (self.k1 + c + self.delta)
+            }
+            Method::Bm25Plus => tf * (self.k1 + 1.0) / (self.k1 * length_norm + tf) + self.delta,
+            Method::TfIdf => 1.0 + tf.ln(),
+            Method::Tf => tf,
+        }
+    }
+
+    fn floors_idf(&self) -> bool {
+        self.idf == Idf::Robertson
+    }
+
+    fn floor_idf(&self, idf: f32, average_idf: f32) -> f32 {
+        if idf < 0.0 { self.epsilon * average_idf } else { idf }
+    }
+
+    fn absent_tf_weight(&self) -> f32 {
+        if !self.exact {
+            return 0.0;
+        }
+
+        match self.method {
+            Method::Robertson | Method::Atire | Method::Lucene | Method::TfIdf | Method::Tf => 0.0,
+            Method::Bm25L => (self.k1 + 1.0) * self.delta / (self.k1 + self.delta),
+            Method::Bm25Plus => self.delta,
+        }
+    }
+
+    /// Length normalization only penalizes longer documents for `b` in `[0, 1]` and non-negative `k1`.
+    /// Cosine normalization depends on every term of a document, so TF-IDF is not bounded.
+    fn max_tf_weight(&self, tf: f32, doc_len: f32, avg_doc_len: f32) -> Option<f32> {
+        let is_monotonic = (0.0..=1.0).contains(&self.b) && self.k1 >= 0.0 && self.delta >= 0.0;
+        (is_monotonic && !self.normalizes_documents()).then(|| self.tf_weight(tf, doc_len, avg_doc_len))
+    }
+
+    /// Only TF-IDF is cosine-normalized, see `Method::TfIdf`.
+    fn normalizes_documents(&self) -> bool {
+        self.method == Method::TfIdf
+    }
+
+    fn as_scoring(&self) -> Option<&Scoring> {
+        Some(self)
+    }
+}
+
+impl Scoring {
+    fn method_idf(&self, df: f32, n_docs: f32) -> f32 {
+        match self.method {
+            Method::Robertson => ((n_docs - df + 0.5) / (df + 0.5)).max(1.0).ln(),
+            Method::Atire | Method::TfIdf => n_docs.ln() - df.ln(),
+            Method::Lucene => (1.0 + (n_docs - df + 0.5) / (df + 0.5)).ln(),
+            Method::Bm25L => ((n_docs + 1.0) / (df + 0.5)).ln(),
+            Method::Bm25Plus => ((n_docs + 1.0) / df).ln(),
+            Method::Tf => 1.0,
+        }
+    }
+
+    pub fn write(&self, writer: &mut Writer) -> io::Result<()> {
+        writer.write_str(self.method.name())?;
+        writer.write_f32(self.k1)?;
+        writer.write_f32(self.b)?;
+        writer.write_f32(self.delta)?;
+        writer.write_str(self.idf.name())?;
+        writer.write_f32(self.epsilon)?;
+        writer.write_u32(self.exact as u32)
+    }
+
+    pub fn read(reader: &mut Reader) -> io::Result<Scoring> {
+        let name = reader.read_str()?;
+        let method = Method::from_name(&name).ok_or_else(|| invalid_data(&format!("unknown method {name:?}")))?;
+
+        let k1 = reader.read_f32()?;
+        let b = reader.read_f32()?;
+        let delta = reader.read_f32()?;
+        let name = reader.read_str()?;
+        let idf = Idf::from_name(&name).ok_or_else(|| invalid_data(&format!("unknown idf {name:?}")))?;
+
+        let epsilon = reader.read_f32()?;
+
+        Ok(Self { method, k1, b, delta, idf, epsilon, exact: reader.read_u32()? != 0 })
+    }
+}
@@ -0,0 +1,316 @@
+use std::collections::HashMap;
+use std::io;
+use std::mem::size_of;
+use std::ops::Range;
+use sprs::TriMatI;
+use crate::persistence::{invalid_data, Buffer, Reader, Writer};
+use crate::positions::Positions;
+use crate::pruning::TermBounds;
+use crate::tokenizer::Corpus;
+
+/// Term-major (CSC) postings: the documents containing term `t` are
+/// `indices[indptr[t]..indptr[t + 1]]`, with matching entries in `values`.
+pub struct MatrixComponents {
+    pub indices: Buffer<u32>,
+    pub values: Buffer<f32>,
+    pub indptr: Buffer<u32>,
+}
+
+impl MatrixComponents {
+    fn build(corpus: &Corpus, n_terms: usize) -> MatrixComponents {
+        let mut rows = Vec::new();
+        let mut cols = Vec::new();
+        let mut term_frequencies = Vec::new();
+
+        for (i, terms) in corpus.documents.iter().enumerate() {
+            let mut term_count: HashMap<u32, f32> = HashMap::new();
+            for &term in terms {
+                *term_count.entry(term).or_insert(0.0) += 1.0;
+            }
+
+            for (term, count) in term_count {
+                rows.push(i as u32);
+                cols.push(term);
+                term_frequencies.push(count);
+            }
+        }
+
+        let tf_matrix = TriMatI::<f32, u32>::from_triplets(
+            (corpus.documents.len(), n_terms),
+            rows,
+            cols,
+            term_frequencies,
+        ).to_csc();
+
+        Self {
+            indices: tf_matrix.indices().to_vec().into(),
+            values: tf_matrix.data().to_vec().into(),
+            indptr: tf_matrix.indptr().into_raw_storage().to_vec().into(),
+        }
+    }
+
+    /// Concatenates the postings of `matrices`, each paired with its number of documents,
+    /// dropping the documents `remap` maps to `None`.
+    fn merge<'a>(
+        matrices: impl Iterator<Item = (&'a MatrixComponents, usize)> + Clone,
+        remap: &[Option<u32>],
+        n_terms: usize,
+    ) -> MatrixComponents {
+        let mut indices = Vec::new();
+        let mut values = Vec::new();
+        let mut indptr = Vec::with_capacity(n_terms + 1);
+        indptr.push(0);
+
+        for term in 0..n_terms {
+            let mut doc_offset = 0;
+            for (matrix, n_docs) in matrices.clone() {
+                let (doc_indices, term_frequencies) = matrix.postings(term);
+                for (&doc, &tf) in doc_indices.iter().zip(term_frequencies) {
+                    if let Some(new_doc) = remap[doc_offset + doc as usize] {
+                        indices.push(new_doc);
+                        values.push(tf);
+                    }
+                }
+                doc_offset += n_docs;
+            }
+            indptr.push(indices.len() as u32);
+        }
+
+        Self { indices: indices.into(), values: values.into(), indptr: indptr.into() }
+    }
+
+    /// Drops the postings lists of the terms `kept` maps to `false`, which must be empty,
+    /// the following terms taking their ids.
+    fn retain_terms(self, kept: &[bool]) -> MatrixComponents {
+        let ends = kept.iter().enumerate().filter(|(_, &is_kept)| is_kept).map(|(term, _)| self.indptr[term + 1]);
+        let indptr: Vec<u32> = std::iter::once(0).chain(ends).collect();
+        Self { indptr: indptr.into(), ..self }
+    }
+
+    pub fn postings(&self, term: usize) -> (&[u32], &[f32]) {
+        let range = self.posting_range(term);
+        (&self.indices[range.clone()], &self.values[range])
+    }
+
+    /// Indices of the postings of `term` in `indices` and `values`.
+    pub fn posting_range(&self, term: usize) -> Range<usize> {
+        if term + 1 >= self.indptr.len() {
+            return 0..0;
+        }
+        self.indptr[term] as usize..self.indptr[term + 1] as usize
+    }
+
+    pub fn doc_frequency(&self, term: usize) -> u32 {
+        if term + 1 >= self.indptr.len() {
+            return 0;
+        }
+        self.indptr[term + 1] - self.indptr[term]
+    }
+
+    pub fn mem(&self) -> usize {
+        self.indices.len() * size_of::<u32>()
+            + self.values.len() * size_of::<f32>()
+            + self.indptr.len() * size_of::<u32>()
+    }
+
+    fn write(&self, writer: &mut Writer) -> io::Result<()> {
+        writer.write_array(&self.indices)?;
+        writer.write_array(&self.values)?;
+        writer.write_array(&self.indptr)
+    }
+
+    fn read(reader: &mut Reader, n_docs: usize, verify: bool) -> io::Result<MatrixComponents> {
+        let matrix = Self {
+            indices: reader.read_array()?,
+            values: reader.read_array()?,
+            indptr: reader.read_array()?,
+        };
+
+        let MatrixComponents { indices, values, indptr } = &matrix;
+        let is_consistent = indices.len() == values.len()
+            && indptr.windows(2).all(|w| w[0] <= w[1])
+            && indptr.last().is_none_or(|&end| end as usize == indices.len())
+            && (!verify || indices.iter().all(|&doc| (doc as usize) < n_docs));
+        if !is_consistent {
+            return Err(invalid_data("inconsistent index matrix"));
+        }
+
+        Ok(matrix)
+    }
+}
+
+/// The postings and lengths of one field of multi-field documents.
+pub struct FieldPostings {
+    pub postings: MatrixComponents,
+    pub lengths: Buffer<f32>,
+}
+
+impl FieldPostings {
+    fn write(&self, writer: &mut Writer) -> io::Result<()> {
+        writer.write_array(&self.lengths)?;
+        self.postings.write(writer)
+    }
+
+    fn read(reader: &mut Reader, n_docs: usize, verify: bool) -> io::Result<FieldPostings> {
+        let lengths: Buffer<f32> = reader.read_array()?;
+        if lengths.len() != n_docs {
+            return Err(invalid_data("inconsistent field lengths"));
+        }
+        Ok(Self { postings: MatrixComponents::read(reader, n_docs, verify)?, lengths })
+    }
+}
+
+/// An immutable batch of documents indexed together. Postings hold raw term frequencies
+/// rather than scores, so documents added later only need a new segment: IDF and average
+/// document length are computed over all segments when querying.
+/// Multi-field documents also get postings per field, `postings` covering all their fields.
+pub struct Segment {
+    pub postings: MatrixComponents,
+    pub doc_lengths: Buffer<f32>,
+    pub fields: Vec<FieldPostings>,
+    /// Token positions of `postings`, for phrase queries.
+    pub positions: Option<Positions>,
+    /// Score bounds of the terms of `postings`, for dynamic pruning.
+    pub bounds: TermBounds,
+}
+
+/// Gap left between the positions of consecutive fields, so that phrases do not match across fields.
+const FIELD_POSITION_GAP: u32 = 100;
+
+fn lengths(corpus: &Corpus) -> Buffer<f32> {
+    corpus.documents.iter().map(|doc| doc.len() as f32).collect::<Vec<_>>().into()
+}
+
+/// Keeps the lengths of the documents `remap` does not map to `None`.
+fn merge_lengths<'a>(lengths: impl Iterator<Item = &'a Buffer<f32>>, remap: &[Option<u32>]) -> Buffer<f32> {
+    lengths
+        .flat_map(|lengths| lengths.iter())
+        .zip(remap)
+        .filter(|(_, new_doc)| new_doc.is_some())
+        .map(|(&len, _)| len)
+        .collect::<Vec<_>>()
+        .into()
+}
+
+impl Segment {
+    pub fn build(corpus: &Corpus, n_terms: usize, with_positions: bool) -> Segment {
+        let positions = with_positions.then(|| {
+            Positions::build((0..corpus.documents.len()).map(|doc| corpus.tokens(doc)), n_terms)
+        });
+
+        let postings = MatrixComponents::build(corpus, n_terms);
+        let doc_lengths = lengths(corpus);
+        Self { bounds: TermBounds::build(&postings, &doc_lengths), postings, doc_lengths, fields: Vec::new(), positions }
+    }
+
+    /// Builds a segment of multi-field documents from one corpus per field.
+    pub fn build_fields(fields: &[Corpus], n_terms: usize, with_positions: bool) -> Segment {
+        let n_docs = fields.first().map_or(0, |corpus| corpus.documents.len());
+        let documents = Corpus {
+            documents: (0..n_docs)
+                .map(|doc| fields.iter().flat_map(|corpus| corpus.documents[doc].iter().copied()).collect())
+                .collect(),
+            positions: Vec::new(),
+        };
+
+        let positions = with_positions.then(|| {
+            let documents = (0..n_docs).map(|doc| {
+                let mut field_start = 0;
+                fields.iter().flat_map(move |corpus| {
+                    let start = field_start;
+                    field_start += corpus.positions[doc].last().map_or(0, |&position| position + 1) + FIELD_POSITION_GAP;
+                    corpus.tokens(doc).map(move |(term, position)| (term, start + position))
+                })
+            });
+            Positions::build(documents, n_terms)
+        });
+
+        Self {
+            fields: fields
+                .iter()
+                .map(|corpus| FieldPostings { postings: MatrixComponents::build(corpus, n_terms), lengths: lengths(corpus) })
+                .collect(),
+            positions,
+            ..Self::build(&documents, n_terms, false)
+        }
+    }
+
+    /// Merges `segments` into a single one, dropping the documents `remap` maps to `None` and the
+    /// terms only they contained. `remap` is indexed by the position of a document across all `segments`.
+    /// Returns the segment with the new id of each term, `None` for the dropped ones.
+    pub fn merge(segments: &[Segment], remap: &[Option<u32>], n_terms: usize) -> (Segment, Vec<Option<u32>>) {
+        let postings = MatrixComponents::merge(segments.iter().map(|segment| (&segment.postings, segment.n_docs())), remap, n_terms);
+        let kept: Vec<bool> = (0..n_terms).map(|term| postings.doc_frequency(term) > 0).collect();
+        let mut next_term = 0..;
+        let term_remap = kept.iter().map(|&is_kept| is_kept.then(|| next_term.next().unwrap())).collect();
+        let postings = postings.retain_terms(&kept);
+
+        let n_fields = segments.first().map_or(0, |segment| segment.fields.len());
+        let fields = (0..n_fields)
+            .map(|field| FieldPostings {
+                postings: MatrixComponents::merge(
+                    segments.iter().map(|segment| (&segment.fields[field].postings, segment.n_docs())),
+                    remap,
+                    n_terms,
+                )
+                .retain_terms(&kept),
+                lengths: merge_lengths(segments.iter().map(|segment| &segment.fields[field].lengths), remap),
+            })
+            .collect();
+
+        let positions = segments.iter().all(|segment| segment.positions.is_some()).then(|| {
+            let parts = segments
+                .iter()
+                .filter_map(|segment| Some((segment.positions.as_ref()?, &segment.postings, segment.n_docs())));
+            Positions::merge(parts, remap, n_terms)
+        });
+
+        let doc_lengths = merge_lengths(segments.iter().map(|segment| &segment.doc_lengths), remap);
+        let segment = Self { bounds: TermBounds::build(&postings, &doc_lengths), postings, doc_lengths, fields, positions };
+        (segment, term_remap)
+    }
+
+    pub fn n_docs(&self) -> usize {
+        self.doc_lengths.len()
+    }
+
+    pub fn mem(&self) -> usize {
+        self.postings.mem()
+            + self.fields.iter().map(|field| field.postings.mem()).sum::<usize>()
+            + self.positions.as_ref().map_or(0, Positions::mem)
+            + self.bounds.mem()
+    }
+
+    pub fn write(&self, writer: &mut Writer) -> io::Result<()> {
+        writer.write_array(&self.doc_lengths)?;
+        self.postings.write(writer)?;
+
+        writer.write_u64(self.fields.len() as u64)?;
+        for field in &self.fields {
+            field.write(writer)?;
+        }
+
+        writer.write_u32(self.positions.is_some() as u32)?;
+        if let Some(positions) = &self.positions {
+            positions.write(writer)?;
+        }
+        self.bounds.write(writer)
+    }
+
+    pub fn read(reader: &mut Reader, verify: bool) -> io::Result<Segment> {
+        let doc_lengths: Buffer<f32> = reader.read_array()?;
+        let postings = MatrixComponents::read(reader, doc_lengths.len(), verify)?;
+
+        let n_fields = reader.read_u64()?;
+        let fields = (0..n_fields)
+            .map(|_| FieldPostings::read(reader, doc_lengths.len(), verify))
+            .collect::<io::Result<_>>()?;
+
+        let positions = match reader.read_u32()? {
+            0 => None,
+            _ => Some(Positions::read(reader, postings.indices.len())?),
+        };
+        let bounds = TermBounds::read(reader, &postings)?;
+        Ok(Self { postings, doc_lengths, fields, positions, bounds })
+    }
+}
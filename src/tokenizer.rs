@@ -1,100 +1,394 @@
+use std::borrow::Cow;
 use std::collections::{HashMap, HashSet};
+use std::io;
+use std::str::FromStr;
 use pyo3::Bound;
+use pyo3::exceptions::PyTypeError;
 use pyo3::prelude::*;
 use pyo3::types::{PyList, PyString};
 use regex::Regex;
 use stopwords::{NLTK, Language, Stopwords};
 use rust_stemmers::{Algorithm, Stemmer};
+use crate::persistence::{invalid_data, Reader, Writer};
 
-pub type Corpus = Vec<Vec<u32>>;
 pub type Vocab = HashMap<String, u32>;
 
-pub struct TokenizeOutput {
-    pub corpus: Corpus,
-    pub vocab: Vocab,
+const DEFAULT_PATTERN: &str = r"(?u)\b\w\w+\b";
+
+/// A token as passed from Python: a string, or an integer id standing for its decimal text,
+/// so that pipelines producing token ids can index them without converting them first.
+#[derive(PartialEq, Eq, Hash)]
+pub enum Token {
+    Text(String),
+    Id(i64),
+}
+
+impl<'py> FromPyObject<'py> for Token {
+    fn extract_bound(obj: &Bound<'py, PyAny>) -> PyResult<Self> {
+        if let Ok(text) = obj.downcast::<PyString>() {
+            return Ok(Token::Text(text.to_str()?.to_string()));
+        }
+
+        obj.extract().map(Token::Id).map_err(|_| match obj.get_type().name() {
+            Ok(name) => PyTypeError::new_err(format!("tokens must be strings or integers, not {name}")),
+            Err(err) => err,
+        })
+    }
+}
+
+impl From<Token> for String {
+    fn from(token: Token) -> String {
+        match token {
+            Token::Text(text) => text,
+            Token::Id(id) => id.to_string(),
+        }
+    }
+}
+
+/// Query tokens, each with its position in the text.
+pub type PositionedTokens = Vec<(String, u32)>;
+
+/// Tokenized documents: the term ids of each document, and the position of each of its tokens.
+/// Positions count the removed tokens, stopwords and words too short for the token pattern,
+/// so that phrases do not match across them.
+#[derive(Default)]
+pub struct Corpus {
+    pub documents: Vec<Vec<u32>>,
+    pub positions: Vec<Vec<u32>>,
+}
+
+impl Corpus {
+    /// The `(term, position)` pairs of document `doc`.
+    pub fn tokens(&self, doc: usize) -> impl Iterator<Item = (u32, u32)> + '_ {
+        self.documents[doc].iter().copied().zip(self.positions[doc].iter().copied())
+    }
+}
+
+/// Counts the words in `text`, runs of alphanumeric characters and underscores.
+fn count_words(text: &str) -> u32 {
+    let mut n_words = 0;
+    let mut in_word = false;
+    for c in text.chars() {
+        let is_word = c.is_alphanumeric() || c == '_';
+        n_words += (is_word && !in_word) as u32;
+        in_word = is_word;
+    }
+    n_words
+}
+
+const STEMMERS: [(&str, Algorithm); 18] = [
+    ("arabic", Algorithm::Arabic),
+    ("danish", Algorithm::Danish),
+    ("dutch", Algorithm::Dutch),
+    ("english", Algorithm::English),
+    ("finnish", Algorithm::Finnish),
+    ("french", Algorithm::French),
+    ("german", Algorithm::German),
+    ("greek", Algorithm::Greek),
+    ("hungarian", Algorithm::Hungarian),
+    ("italian", Algorithm::Italian),
+    ("norwegian", Algorithm::Norwegian),
+    ("portuguese", Algorithm::Portuguese),
+    ("romanian", Algorithm::Romanian),
+    ("russian", Algorithm::Russian),
+    ("spanish", Algorithm::Spanish),
+    ("swedish", Algorithm::Swedish),
+    ("tamil", Algorithm::Tamil),
+    ("turkish", Algorithm::Turkish),
+];
+
+/// Looks up a Snowball algorithm by its lowercase language name.
+pub fn stemmer_algorithm(name: &str) -> Option<Algorithm> {
+    STEMMERS.iter()
+        .find(|(stemmer, _)| *stemmer == name)
+        .map(|(_, algorithm)| *algorithm)
+}
+
+/// Stopwords as passed from Python: the name of a language with an NLTK list, or an iterable of words.
+pub enum StopWords {
+    Language(String),
+    Words(Vec<String>),
+}
+
+impl<'py> FromPyObject<'py> for StopWords {
+    fn extract_bound(obj: &Bound<'py, PyAny>) -> PyResult<Self> {
+        if let Ok(name) = obj.downcast::<PyString>() {
+            return Ok(StopWords::Language(name.to_str()?.to_string()));
+        }
+
+        let words = obj.try_iter()?.map(|word| word?.extract()).collect::<PyResult<_>>()?;
+        Ok(StopWords::Words(words))
+    }
+}
+
+impl StopWords {
+    pub fn resolve(self) -> Result<Vec<String>, String> {
+        match self {
+            StopWords::Language(name) => Language::from_str(&name.to_lowercase())
+                .ok()
+                .and_then(NLTK::stopwords)
+                .map(|words| words.iter().map(|&word| word.to_string()).collect())
+                .ok_or_else(|| format!("no stopwords available for language {name:?}")),
+            StopWords::Words(words) => Ok(words.iter().map(|word| word.to_lowercase()).collect()),
+        }
+    }
+}
+
+/// Everything needed to rebuild an identical tokenizer, so that a reloaded index
+/// tokenizes queries the same way its documents were tokenized. A Python splitter
+/// cannot be serialized, only the fact that one must be provided again on load.
+#[derive(Clone)]
+pub struct TokenizerConfig {
+    pub pattern: String,
+    pub stop_words: Vec<String>,
+    pub stemmer: Option<Algorithm>,
+    pub custom_splitter: bool,
+}
+
+impl Default for TokenizerConfig {
+    fn default() -> Self {
+        Self {
+            pattern: DEFAULT_PATTERN.to_string(),
+            stop_words: NLTK::stopwords(Language::English)
+                .unwrap()
+                .iter()
+                .map(|&s| s.to_string())
+                .collect(),
+            stemmer: Some(Algorithm::English),
+            custom_splitter: false,
+        }
+    }
+}
+
+impl TokenizerConfig {
+    pub fn write(&self, writer: &mut Writer) -> io::Result<()> {
+        let stemmer = match self.stemmer {
+            Some(stemmer) => STEMMERS.iter().find(|(_, algorithm)| *algorithm == stemmer).unwrap().0,
+            None => "",
+        };
+
+        writer.write_str(&self.pattern)?;
+        writer.write_str(stemmer)?;
+        writer.write_u64(self.stop_words.len() as u64)?;
+        for word in &self.stop_words {
+            writer.write_str(word)?;
+        }
+        writer.write_u32(self.custom_splitter as u32)
+    }
+
+    pub fn read(reader: &mut Reader) -> io::Result<TokenizerConfig> {
+        let pattern = reader.read_str()?;
+        let stemmer = reader.read_str()?;
+        let stemmer = match stemmer.as_str() {
+            "" => None,
+            name => Some(stemmer_algorithm(name).ok_or_else(|| invalid_data(&format!("unknown stemmer {name:?}")))?),
+        };
+
+        let n_stop_words = reader.read_u64()?;
+        let stop_words = (0..n_stop_words).map(|_| reader.read_str()).collect::<io::Result<_>>()?;
+        let custom_splitter = reader.read_u32()? != 0;
+
+        Ok(Self { pattern, stop_words, stemmer, custom_splitter })
+    }
 }
 
 pub struct Tokenizer {
+    config: TokenizerConfig,
     word_pattern: Regex,
     stop_words: HashSet<String>,
-    stemmer: Stemmer,
+    stemmer: Option<Stemmer>,
+    splitter: Option<Py<PyAny>>,
+}
+
+impl Default for Tokenizer {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Tokenizer {
     pub fn new() -> Tokenizer {
-        let stop_words: HashSet<_> = NLTK::stopwords(Language::English)
-            .unwrap()
-            .into_iter()
-            .map(|&s| s.to_string())
-            .collect();
+        Self::from_config(TokenizerConfig::default(), None).unwrap()
+    }
 
-        Self {
-            word_pattern: Regex::new(r"(?u)\b\w\w+\b").unwrap(),
-            stop_words,
-            stemmer: Stemmer::create(Algorithm::English),
-        }
+    /// `splitter` is a Python callable mapping a text to its tokens, used instead of `word_pattern`.
+    /// Its tokens still go through stopword removal, whatever their case, and stemming.
+    pub fn from_config(mut config: TokenizerConfig, splitter: Option<Py<PyAny>>) -> Result<Tokenizer, regex::Error> {
+        config.custom_splitter = splitter.is_some();
+
+        Ok(Self {
+            word_pattern: Regex::new(&config.pattern)?,
+            stop_words: config.stop_words.iter().cloned().collect(),
+            stemmer: config.stemmer.map(Stemmer::create),
+            splitter,
+            config,
+        })
     }
 
-    pub fn perform_simple(&self, text: &String) -> Vec<String> {
-        self.word_pattern
-            .find_iter(&text.to_lowercase())
-            .map(|token| token.as_str())
-            .filter(|token| !self.stop_words.contains(*token))
-            .map(|token| self.stemmer.stem(token).to_string())
-            .collect()
+    pub fn config(&self) -> &TokenizerConfig {
+        &self.config
     }
 
-    pub fn perform<'py>(&self, texts: &Bound<'py, PyList>) -> TokenizeOutput {
-        let mut vocab: Vocab = HashMap::new();
-        let mut corpus: Corpus = Vec::with_capacity(texts.len());
-        let mut id = 0;
+    /// Whether tokenizing needs the GIL, in which case it cannot run on the rayon pool.
+    pub fn calls_python(&self) -> bool {
+        self.splitter.is_some()
+    }
 
-        for text in texts.iter() {
-            let lowercased = text
-                .downcast::<PyString>()
-                .map(|item| item.to_str().unwrap().to_lowercase())
-                .unwrap();
+    fn stem<'a>(&self, token: &'a str) -> Cow<'a, str> {
+        match &self.stemmer {
+            Some(stemmer) => stemmer.stem(token),
+            None => Cow::Borrowed(token),
+        }
+    }
 
-            let mut doc_tokens = Vec::new();
-            for token in self.word_pattern.find_iter(&lowercased) {
-                let token = token.as_str();
-
-                if !self.stop_words.contains(token) {
-                    let token_id = match vocab.get(token) {
-                        Some(&existing_id) => existing_id,
-                        None => {
-                            let new_id = id;
-                            vocab.insert(token.to_owned(), new_id);
-                            id += 1;
-                            new_id
-                        }
-                    };
-                    doc_tokens.push(token_id);
+    fn split(splitter: &Py<PyAny>, text: &Bound<'_, PyAny>) -> PyResult<Vec<String>> {
+        splitter.bind(text.py()).call1((text,))?.extract()
+    }
+
+    /// Calls `on_token` with every token of `text` that is not a stopword, before stemming, and its
+    /// position. Removed tokens still take a position: stopwords, and with the token pattern the words
+    /// it skips between two tokens, such as single letters with the default one.
+    fn for_each_token(&self, text: &Bound<'_, PyAny>, mut on_token: impl FnMut(&str, u32)) -> PyResult<()> {
+        match &self.splitter {
+            Some(splitter) => {
+                // Stopwords are lowercase, while the tokens of a splitter keep their case.
+                for (token, position) in Tokenizer::split(splitter, text)?.iter().zip(0..) {
+                    if !self.stop_words.contains(token.to_lowercase().as_str()) {
+                        on_token(token, position);
+                    }
                 }
             }
+            None => self.for_each_native_token(text.downcast::<PyString>()?.to_str()?, on_token),
+        }
+        Ok(())
+    }
 
-            corpus.push(doc_tokens);
+    /// Regex tokenization for `for_each_token`, usable without the GIL.
+    fn for_each_native_token(&self, text: &str, mut on_token: impl FnMut(&str, u32)) {
+        let lowercased = text.to_lowercase();
+        let (mut position, mut previous_end) = (0, 0);
+        for token in self.word_pattern.find_iter(&lowercased) {
+            position += count_words(&lowercased[previous_end..token.start()]);
+            previous_end = token.end();
+            if !self.stop_words.contains(token.as_str()) {
+                on_token(token.as_str(), position);
+            }
+            position += 1;
         }
+    }
 
-        let unique_terms: Vec<String> = vocab.keys().cloned().collect();
-        let stemmed_terms: Vec<String> = unique_terms.iter().map(|term| self.stemmer.stem(term).to_string()).collect();
-        let unique_stemmed_terms: HashSet<_> = stemmed_terms.clone().into_iter().collect();
+    /// Tokenizes a query text, each stemmed token with its position, see `for_each_token`.
+    pub fn perform_simple(&self, py: Python<'_>, text: &str) -> PyResult<PositionedTokens> {
+        if self.splitter.is_none() {
+            return Ok(self.perform_simple_native(text));
+        }
 
-        let stemmed_vocab: HashMap<String, u32> = unique_stemmed_terms.iter().enumerate().map(|(i, stemmed_term)| (stemmed_term.clone(), i as u32)).collect();
+        let mut tokens = Vec::new();
+        self.for_each_token(PyString::new(py, text).as_any(), |token, position| tokens.push((self.stem(token).into_owned(), position)))?;
+        Ok(tokens)
+    }
+
+    /// Regex tokenization, usable without the GIL when no Python splitter is configured.
+    pub fn perform_simple_native(&self, text: &str) -> PositionedTokens {
+        let mut tokens = Vec::new();
+        self.for_each_native_token(text, |token, position| tokens.push((self.stem(token).into_owned(), position)));
+        tokens
+    }
 
-        let vocab_to_stemmed_vocab: HashMap<u32, u32> = unique_terms
+    /// Maps already tokenized documents to term ids, extending `vocab` with any token it does not know yet.
+    /// Their tokens take consecutive positions.
+    pub fn encode(documents: &[Vec<String>], vocab: &mut Vocab) -> Corpus {
+        let terms = documents
             .iter()
-            .zip(stemmed_terms.iter())
-            .map(|(term, stem)| (*vocab.get(term).unwrap(), *stemmed_vocab.get(stem).unwrap()))
+            .map(|tokens| {
+                tokens.iter()
+                    .map(|token| {
+                        let next_id = vocab.len() as u32;
+                        match vocab.get(token) {
+                            Some(&existing_id) => existing_id,
+                            None => *vocab.entry(token.clone()).or_insert(next_id),
+                        }
+                    })
+                    .collect()
+            })
             .collect();
 
-        for terms in corpus.iter_mut(){
+        Corpus { documents: terms, positions: documents.iter().map(|tokens| (0..tokens.len() as u32).collect()).collect() }
+    }
+
+    /// Tokenizes a batch of documents, extending `vocab` with any stem it does not know yet.
+    pub fn perform(&self, texts: &Bound<'_, PyList>, vocab: &mut Vocab) -> PyResult<Corpus> {
+        let mut raw_vocab: Vocab = HashMap::new();
+        let mut corpus = Corpus::default();
+        let mut id = 0;
+
+        for text in texts.iter() {
+            let mut doc_tokens = Vec::new();
+            let mut doc_positions = Vec::new();
+            self.for_each_token(&text, |token, position| {
+                let token_id = match raw_vocab.get(token) {
+                    Some(&existing_id) => existing_id,
+                    None => {
+                        let new_id = id;
+                        raw_vocab.insert(token.to_owned(), new_id);
+                        id += 1;
+                        new_id
+                    }
+                };
+                doc_tokens.push(token_id);
+                doc_positions.push(position);
+            })?;
+
+            corpus.documents.push(doc_tokens);
+            corpus.positions.push(doc_positions);
+        }
+
+        let raw_to_stemmed: HashMap<u32, u32> = raw_vocab
+            .iter()
+            .map(|(term, &raw_id)| {
+                let stem = self.stem(term);
+                let next_id = vocab.len() as u32;
+                let stemmed_id = match vocab.get(stem.as_ref()) {
+                    Some(&existing_id) => existing_id,
+                    None => *vocab.entry(stem.into_owned()).or_insert(next_id),
+                };
+                (raw_id, stemmed_id)
+            })
+            .collect();
+
+        for terms in corpus.documents.iter_mut() {
             for term in terms.iter_mut() {
-                *term = *vocab_to_stemmed_vocab.get(term).unwrap();
+                *term = *raw_to_stemmed.get(term).unwrap();
             }
         }
 
+        Ok(corpus)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn positioned(tokens: &[(&str, u32)]) -> PositionedTokens {
+        tokens.iter().map(|&(token, position)| (token.to_string(), position)).collect()
+    }
+
+    #[test]
+    fn positions_count_removed_tokens() {
+        let tokenizer = Tokenizer::new();
+        assert_eq!(tokenizer.perform_simple_native("The heart of the attack"), positioned(&[("heart", 1), ("attack", 4)]));
+        assert_eq!(
+            tokenizer.perform_simple_native("heart x y z attack, heart-attack"),
+            positioned(&[("heart", 0), ("attack", 4), ("heart", 5), ("attack", 6)])
+        );
+    }
 
-        TokenizeOutput { corpus, vocab: stemmed_vocab }
+    #[test]
+    fn pretokenized_positions_are_consecutive() {
+        let mut vocab = Vocab::new();
+        let corpus = Tokenizer::encode(&[vec!["a".to_string(), "b".to_string(), "a".to_string()]], &mut vocab);
+        assert_eq!(corpus.documents, [vec![0, 1, 0]]);
+        assert_eq!(corpus.positions, [vec![0, 1, 2]]);
     }
 }